@@ -1,11 +1,21 @@
 use hex::ToHex;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    sync::Arc,
+};
 
-use super::resource::Resource;
+use super::{object::Object, resource::Resource};
 
 pub const CHUNK_SIZE: u64 = 1024 * 1024 * 4;
 
+/// Stands in for a chunk that is entirely zero, which is never stored or fetched.
+///
+/// Holes in sparse files become these, and so do chunks of zeros that were written out in full,
+/// so that a file's hash only depends on its contents.
+pub const HOLE: Object = Object { hash: [0; 32] };
+
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Chunk {
     pub data: Vec<u8>,
@@ -18,6 +28,32 @@ impl Chunk {
             .map(|d| Chunk { data: d.to_vec() })
             .collect()
     }
+
+    pub fn is_zero(&self) -> bool {
+        self.data.iter().all(|byte| *byte == 0)
+    }
+
+    /// Splits the contents of a reader into chunks, reading one chunk at a time.
+    pub fn chunks_from_reader<R: Read>(reader: R) -> ChunkReader<R> {
+        ChunkReader { reader }
+    }
+}
+
+pub struct ChunkReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = Vec::new();
+        match self.reader.by_ref().take(CHUNK_SIZE).read_to_end(&mut data) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(Chunk { data })),
+            Err(err) => Some(Err(err)),
+        }
+    }
 }
 
 impl Debug for Chunk {
@@ -0,0 +1,189 @@
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{directory::Directory, object::Object, resource::Resource, ContentAddressedStore};
+
+/// A snapshot of a tree, linked to the snapshots it was derived from.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct Commit {
+    pub tree: Object,
+    pub parents: Vec<Object>,
+    pub author: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl Commit {
+    /// Creates a commit made now.
+    pub fn new(tree: Object, parents: Vec<Object>, author: &str, message: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        Commit {
+            tree,
+            parents,
+            author: author.to_string(),
+            timestamp,
+            message: message.to_string(),
+        }
+    }
+
+    /// Presents the commit as a directory containing its tree (as `tree`) and its parents (as
+    /// `parent-1`, `parent-2` and so on), so that it can be browsed like one.
+    pub fn to_directory(&self) -> Directory {
+        let mut contents = BTreeMap::from([("tree".to_string(), self.tree)]);
+        for (i, parent) in self.parents.iter().enumerate() {
+            contents.insert(format!("parent-{}", i + 1), *parent);
+        }
+
+        Directory::new(contents)
+    }
+}
+
+/// Lists `head` and every commit reachable from it, newest first.
+///
+/// Commits that can't be found (for example because only part of a history was replicated) are
+/// left out, along with any ancestors only reachable through them.
+pub fn history<T: ContentAddressedStore>(store: &T, head: Object) -> Vec<(Object, Arc<Commit>)> {
+    let mut queue = BinaryHeap::new();
+    let mut found: HashMap<Object, Arc<Commit>> = HashMap::new();
+    if let Some(commit) = store.get::<Arc<Commit>>(&head) {
+        queue.push((commit.timestamp, head));
+        found.insert(head, commit);
+    }
+
+    let mut commits = Vec::new();
+    while let Some((_, object)) = queue.pop() {
+        let commit = found[&object].clone();
+        for parent in &commit.parents {
+            if found.contains_key(parent) {
+                continue;
+            }
+            if let Some(parent_commit) = store.get::<Arc<Commit>>(parent) {
+                queue.push((parent_commit.timestamp, *parent));
+                found.insert(*parent, parent_commit);
+            }
+        }
+        commits.push((object, commit));
+    }
+
+    commits
+}
+
+impl<'a> TryFrom<&'a Resource> for &'a Commit {
+    type Error = ();
+
+    fn try_from(value: &'a Resource) -> Result<Self, Self::Error> {
+        if let Resource::Commit(commit) = value {
+            Ok(commit)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl TryFrom<Resource> for Arc<Commit> {
+    type Error = ();
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        if let Resource::Commit(commit) = value {
+            Ok(commit)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl From<Arc<Commit>> for Resource {
+    fn from(val: Arc<Commit>) -> Self {
+        Resource::Commit(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::fs::LocalStore;
+
+    use super::*;
+
+    fn commit(store: &mut LocalStore, parents: &[Object], timestamp: u64) -> Object {
+        let commit = Commit {
+            tree: Object::new([timestamp as u8; 32]),
+            parents: parents.to_vec(),
+            author: "me".to_string(),
+            timestamp,
+            message: format!("at {timestamp}"),
+        };
+        let resource: Resource = Arc::new(commit).into();
+        let object = Object::from(&resource);
+        store.add_resource(resource);
+        object
+    }
+
+    fn objects(history: Vec<(Object, Arc<Commit>)>) -> Vec<Object> {
+        history.into_iter().map(|(object, _)| object).collect()
+    }
+
+    #[test]
+    fn lists_history_newest_first() {
+        let mut store = LocalStore::new();
+        let first = commit(&mut store, &[], 1);
+        let second = commit(&mut store, &[first], 2);
+        let third = commit(&mut store, &[second], 3);
+
+        assert_eq!(objects(history(&store, third)), [third, second, first]);
+        assert_eq!(objects(history(&store, second)), [second, first]);
+    }
+
+    #[test]
+    fn lists_both_sides_of_merges_once() {
+        let mut store = LocalStore::new();
+        let root = commit(&mut store, &[], 1);
+        let theirs = commit(&mut store, &[root], 2);
+        let ours = commit(&mut store, &[root], 3);
+        let older = commit(&mut store, &[theirs], 4);
+        let merge = commit(&mut store, &[ours, older], 5);
+
+        assert_eq!(
+            objects(history(&store, merge)),
+            [merge, older, ours, theirs, root]
+        );
+    }
+
+    #[test]
+    fn leaves_out_missing_commits() {
+        let mut store = LocalStore::new();
+        let missing = Object::new([9; 32]);
+        let first = commit(&mut store, &[], 1);
+        let head = commit(&mut store, &[missing, first], 2);
+
+        assert_eq!(objects(history(&store, head)), [head, first]);
+        assert!(history(&store, missing).is_empty());
+        let file = store.create_file(b"not a commit");
+        assert!(history(&store, file).is_empty());
+    }
+
+    #[test]
+    fn presents_commits_as_directories() {
+        let mut store = LocalStore::new();
+        let first = commit(&mut store, &[], 1);
+        let second = commit(&mut store, &[], 2);
+        let merge = commit(&mut store, &[first, second], 3);
+        let merge: Arc<Commit> = store.get(&merge).unwrap();
+
+        let names: Vec<_> = merge
+            .to_directory()
+            .get_children(&store)
+            .map(|entry| entry.unwrap().name)
+            .collect();
+        assert_eq!(names, ["parent-1", "parent-2", "tree"]);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    fmt::{self, Display},
+    ops::Range,
+    sync::Arc,
+};
+
+use super::{
+    chunk::CHUNK_SIZE,
+    directory::{Directory, DirectoryEntry, DirectoryNode},
+    error::PathResolutionError,
+    file::File,
+    object::Object,
+    resource::Resource,
+    ContentAddressedStore,
+};
+
+/// A difference between two trees, with paths relative to their roots.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Change {
+    Added {
+        path: String,
+        object: Object,
+    },
+    Removed {
+        path: String,
+        object: Object,
+    },
+    Modified {
+        path: String,
+        old: Object,
+        new: Object,
+        /// Byte ranges whose chunks differ, if they were asked for
+        changed_bytes: Option<Vec<Range<u64>>>,
+    },
+    Renamed {
+        from: String,
+        to: String,
+        object: Object,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { path, .. } => write!(f, "A {path}"),
+            Change::Removed { path, .. } => write!(f, "D {path}"),
+            Change::Modified {
+                path,
+                changed_bytes,
+                ..
+            } => {
+                write!(f, "M {path}")?;
+                if let Some(ranges) = changed_bytes {
+                    let ranges = ranges
+                        .iter()
+                        .map(|range| format!("{}..{}", range.start, range.end))
+                        .collect::<Vec<_>>();
+                    write!(f, " (bytes {})", ranges.join(", "))?;
+                }
+                Ok(())
+            }
+            Change::Renamed { from, to, .. } => write!(f, "R {from} -> {to}"),
+        }
+    }
+}
+
+/// Lists the changes that turn the tree at `old` into the tree at `new`.
+///
+/// Both trees are walked side by side, skipping any subtrees whose hashes are equal. An entry
+/// that was removed in one place and added with the same hash in another is reported as a rename.
+/// If `chunks` is set, modified files also report which of their bytes changed.
+pub fn diff<T: ContentAddressedStore>(
+    store: &T,
+    old: &Object,
+    new: &Object,
+    chunks: bool,
+) -> Result<Vec<Change>, PathResolutionError> {
+    let mut tree_diff = TreeDiff {
+        store,
+        chunks,
+        changes: Vec::new(),
+    };
+    tree_diff.diff_objects("", *old, *new)?;
+
+    Ok(detect_renames(tree_diff.changes))
+}
+
+struct TreeDiff<'a, T: ContentAddressedStore> {
+    store: &'a T,
+    chunks: bool,
+    changes: Vec<Change>,
+}
+
+impl<'a, T: ContentAddressedStore> TreeDiff<'a, T> {
+    fn diff_objects(
+        &mut self,
+        path: &str,
+        old: Object,
+        new: Object,
+    ) -> Result<(), PathResolutionError> {
+        if old == new {
+            return Ok(());
+        }
+
+        match (self.fetch(path, &old)?, self.fetch(path, &new)?) {
+            (Resource::Directory(old_dir), Resource::Directory(new_dir)) => {
+                self.diff_directories(path, &old_dir, &new_dir)
+            }
+            (Resource::File(old_file), Resource::File(new_file)) => {
+                let changed_bytes = match self.chunks {
+                    true => Some(self.changed_byte_ranges(path, &old_file, &new_file)?),
+                    false => None,
+                };
+                self.changes.push(Change::Modified {
+                    path: path.to_string(),
+                    old,
+                    new,
+                    changed_bytes,
+                });
+                Ok(())
+            }
+            _ => {
+                self.changes.push(Change::Removed {
+                    path: path.to_string(),
+                    object: old,
+                });
+                self.changes.push(Change::Added {
+                    path: path.to_string(),
+                    object: new,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Walks the entries of both directories in order of name.
+    ///
+    /// Shards are only opened when they differ, so large directories with few changes are mostly
+    /// skipped over. Since shard boundaries depend only on the names around them, the shards of
+    /// unchanged ranges of entries line up on both sides.
+    fn diff_directories(
+        &mut self,
+        path: &str,
+        old: &Directory,
+        new: &Directory,
+    ) -> Result<(), PathResolutionError> {
+        // the next node on each side is the last one
+        let mut old_nodes = old.nodes();
+        let mut new_nodes = new.nodes();
+        old_nodes.reverse();
+        new_nodes.reverse();
+
+        loop {
+            match (old_nodes.last(), new_nodes.last()) {
+                (None, None) => return Ok(()),
+                (Some(DirectoryNode::Shard(old)), Some(DirectoryNode::Shard(new)))
+                    if old.object == new.object =>
+                {
+                    old_nodes.pop();
+                    new_nodes.pop();
+                }
+                // open the bigger shard first, since the smaller one may match something in it
+                (Some(DirectoryNode::Shard(old)), Some(DirectoryNode::Shard(new)))
+                    if new.count > old.count =>
+                {
+                    self.open_shard(path, &mut new_nodes)?
+                }
+                (Some(DirectoryNode::Shard(_)), _) => self.open_shard(path, &mut old_nodes)?,
+                (_, Some(DirectoryNode::Shard(_))) => self.open_shard(path, &mut new_nodes)?,
+                _ => {
+                    // only entries are left at the front, so they can be compared by name
+                    let order = match (old_nodes.last(), new_nodes.last()) {
+                        (Some(DirectoryNode::Entry(old)), Some(DirectoryNode::Entry(new))) => {
+                            old.name.cmp(&new.name)
+                        }
+                        (Some(_), _) => Ordering::Less,
+                        (None, _) => Ordering::Greater,
+                    };
+                    let old = (order != Ordering::Greater).then(|| pop_entry(&mut old_nodes));
+                    let new = (order != Ordering::Less).then(|| pop_entry(&mut new_nodes));
+
+                    match (old, new) {
+                        (Some(old), Some(new)) => {
+                            self.diff_objects(&child_path(path, &old.name), old.file, new.file)?
+                        }
+                        (Some(old), None) => self.changes.push(Change::Removed {
+                            path: child_path(path, &old.name),
+                            object: old.file,
+                        }),
+                        (None, Some(new)) => self.changes.push(Change::Added {
+                            path: child_path(path, &new.name),
+                            object: new.file,
+                        }),
+                        (None, None) => unreachable!("one side has an entry"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces the shard at the end of `nodes` with its own nodes.
+    fn open_shard(
+        &self,
+        path: &str,
+        nodes: &mut Vec<DirectoryNode>,
+    ) -> Result<(), PathResolutionError> {
+        let Some(DirectoryNode::Shard(shard)) = nodes.pop() else {
+            unreachable!("only shards are opened");
+        };
+        let directory: Arc<Directory> =
+            self.store
+                .get(&shard.object)
+                .ok_or(PathResolutionError::new(&format!(
+                    "unable to find shard of '{path}' starting at '{}'",
+                    shard.first
+                )))?;
+
+        nodes.extend(directory.nodes().into_iter().rev());
+        Ok(())
+    }
+
+    fn changed_byte_ranges(
+        &self,
+        path: &str,
+        old: &File,
+        new: &File,
+    ) -> Result<Vec<Range<u64>>, PathResolutionError> {
+        // inline files have no chunks to compare
+        if old.is_inline() || new.is_inline() {
+            let whole_file = 0..old.size.max(new.size);
+            return Ok(vec![whole_file]);
+        }
+
+        let chunks = |file: &File| {
+            file.chunks(self.store, 0..file.chunk_count())
+                .map_err(|_| PathResolutionError::new(&format!("unable to read '{path}'")))
+        };
+
+        Ok(changed_byte_ranges(
+            &chunks(old)?,
+            &chunks(new)?,
+            old.size,
+            new.size,
+        ))
+    }
+
+    fn fetch(&self, path: &str, object: &Object) -> Result<Resource, PathResolutionError> {
+        self.store
+            .get_resource(object)
+            .ok_or(PathResolutionError::new(&format!(
+                "unable to find '{path}'"
+            )))
+    }
+}
+
+fn child_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{path}/{name}")
+    }
+}
+
+/// Removes the entry at the end of `nodes`, which must be one.
+fn pop_entry(nodes: &mut Vec<DirectoryNode>) -> DirectoryEntry {
+    match nodes.pop() {
+        Some(DirectoryNode::Entry(entry)) => entry,
+        _ => unreachable!("the last node should be an entry"),
+    }
+}
+
+/// Pairs up removals and additions of the same object.
+fn detect_renames(changes: Vec<Change>) -> Vec<Change> {
+    let mut removed: HashMap<Object, Vec<String>> = HashMap::new();
+    for change in &changes {
+        if let Change::Removed { path, object } = change {
+            removed.entry(*object).or_default().push(path.clone());
+        }
+    }
+
+    let mut renamed_from = BTreeSet::new();
+    let mut with_renames = Vec::new();
+    for change in changes {
+        match change {
+            Change::Added { path, object } => match removed.get_mut(&object).and_then(Vec::pop) {
+                Some(from) => {
+                    renamed_from.insert(from.clone());
+                    with_renames.push(Change::Renamed {
+                        from,
+                        to: path,
+                        object,
+                    });
+                }
+                None => with_renames.push(Change::Added { path, object }),
+            },
+            change => with_renames.push(change),
+        }
+    }
+
+    with_renames.retain(|change| match change {
+        Change::Removed { path, .. } => !renamed_from.contains(path),
+        _ => true,
+    });
+
+    with_renames
+}
+
+/// Returns the byte ranges covered by chunks that differ between `old` and `new`.
+fn changed_byte_ranges(
+    old: &[Object],
+    new: &[Object],
+    old_size: u64,
+    new_size: u64,
+) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+
+    for (i, chunk) in new.iter().enumerate() {
+        if old.get(i) == Some(chunk) {
+            continue;
+        }
+
+        let start = i as u64 * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(new_size);
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+
+    // truncated files lose everything past their new end
+    if old_size > new_size {
+        ranges.push(new_size..old_size);
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, collections::BTreeMap};
+
+    use crate::store::fs::LocalStore;
+
+    use super::*;
+
+    /// Counts how many resources are fetched from the store it wraps.
+    struct CountingStore {
+        store: LocalStore,
+        fetches: Cell<usize>,
+    }
+
+    impl ContentAddressedStore for CountingStore {
+        fn get_resource(&self, object: &Object) -> Option<Resource> {
+            self.fetches.set(self.fetches.get() + 1);
+            self.store.get_resource(object)
+        }
+
+        fn has(&self, object: &Object) -> bool {
+            self.store.has(object)
+        }
+
+        fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError> {
+            self.store.accessible_objects()
+        }
+    }
+
+    fn directory(store: &mut LocalStore, entries: &[(&str, Object)]) -> Object {
+        let entries = entries
+            .iter()
+            .map(|(name, object)| (name.to_string(), *object))
+            .collect();
+        store.create_directory(entries)
+    }
+
+    #[test]
+    fn lists_added_removed_and_modified_entries() {
+        let mut store = LocalStore::new();
+        let (one, two, three) = (
+            store.create_file(b"one"),
+            store.create_file(b"two"),
+            store.create_file(b"three"),
+        );
+        let old_sub = directory(&mut store, &[("x", one)]);
+        let new_sub = directory(&mut store, &[("x", two)]);
+        let old = directory(&mut store, &[("a", one), ("b", one), ("sub", old_sub)]);
+        let new = directory(&mut store, &[("b", two), ("c", three), ("sub", new_sub)]);
+
+        let changes = diff(&store, &old, &new, false).unwrap();
+        let changes: Vec<_> = changes.iter().map(Change::to_string).collect();
+
+        assert_eq!(changes, ["D a", "M b", "A c", "M sub/x"]);
+    }
+
+    #[test]
+    fn pairs_up_renames() {
+        let mut store = LocalStore::new();
+        let one = store.create_file(b"one");
+        let old = directory(&mut store, &[("a", one)]);
+        let new = directory(&mut store, &[("b", one)]);
+
+        let changes = diff(&store, &old, &new, false).unwrap();
+
+        assert_eq!(
+            changes,
+            [Change::Renamed {
+                from: "a".to_string(),
+                to: "b".to_string(),
+                object: one,
+            }]
+        );
+    }
+
+    #[test]
+    fn only_opens_shards_that_differ() {
+        let mut store = LocalStore::new();
+        let file = store.create_file(b"same");
+        let changed = store.create_file(b"changed");
+        let added = store.create_file(b"added");
+        let contents: BTreeMap<_, _> = (0..20_000)
+            .map(|i| (format!("entry-{i:06}"), file))
+            .collect();
+        let old = store.create_directory(contents.clone());
+
+        let mut new_contents = contents;
+        new_contents.insert("entry-012345".to_string(), changed);
+        new_contents.remove("entry-000010");
+        new_contents.insert("entry-019999-new".to_string(), added);
+        let new = store.create_directory(new_contents);
+
+        let store = CountingStore {
+            store,
+            fetches: Cell::new(0),
+        };
+        let changes = diff(&store, &old, &new, false).unwrap();
+        let changes: Vec<_> = changes.iter().map(Change::to_string).collect();
+
+        assert_eq!(
+            changes,
+            ["D entry-000010", "M entry-012345", "A entry-019999-new"]
+        );
+        // both directories, the shards around each change and the modified files
+        assert!(store.fetches.get() < 20, "{} fetches", store.fetches.get());
+    }
+
+    #[test]
+    fn finds_changed_byte_ranges() {
+        let chunk = |n| Object::new([n; 32]);
+        let old = [chunk(0), chunk(1), chunk(2), chunk(3)];
+        let new = [chunk(0), chunk(9), chunk(9), chunk(3), chunk(4)];
+
+        let ranges = changed_byte_ranges(&old, &new, 4 * CHUNK_SIZE, 4 * CHUNK_SIZE + 10);
+
+        assert_eq!(
+            ranges,
+            [
+                CHUNK_SIZE..3 * CHUNK_SIZE,
+                4 * CHUNK_SIZE..4 * CHUNK_SIZE + 10
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_truncated_bytes_as_changed() {
+        let chunk = |n| Object::new([n; 32]);
+        let old = [chunk(0), chunk(1)];
+        let new = [chunk(0)];
+
+        let ranges = changed_byte_ranges(&old, &new, 2 * CHUNK_SIZE, CHUNK_SIZE);
+
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], CHUNK_SIZE..2 * CHUNK_SIZE);
+    }
+}
@@ -1,12 +1,15 @@
 use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::sync::Arc;
+use std::vec;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use super::error::{FilesystemError, PathResolutionError};
 use super::object::Object;
 use super::resource::Resource;
+use super::ContentAddressedStore;
 
 #[derive(PartialEq, Eq, Clone, Hash, Serialize, Deserialize, Debug)]
 pub struct DirectoryEntry {
@@ -20,18 +23,119 @@ impl Display for DirectoryEntry {
     }
 }
 
+/// Directories with more entries than this are split into shards.
+pub const SHARD_THRESHOLD: usize = 1_024;
+
+/// Average number of entries (or, above the leaves, shards) in each shard.
+const SHARD_FANOUT: u64 = 256;
+
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub struct Directory {
     contents: BTreeMap<String, Object>,
+    /// Shards holding the entries of a large directory, in order of name. A directory has either
+    /// contents or shards, never both.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    shards: Vec<Shard>,
+}
+
+/// A reference to a directory holding a contiguous range of a sharded directory's entries.
+///
+/// Shards are directories themselves, so they can be nested to any depth.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct Shard {
+    /// Name of the first entry in the shard
+    pub first: String,
+    /// Number of entries in the shard, including those in nested shards
+    pub count: u64,
+    pub object: Object,
 }
 
 impl Directory {
     pub fn new(contents: BTreeMap<String, Object>) -> Self {
-        Directory { contents }
+        Directory {
+            contents,
+            shards: Vec::new(),
+        }
+    }
+
+    /// Creates a directory from its entries, splitting it into shards if it is large.
+    ///
+    /// Returns the directory along with the shard resources it refers to, which need to be
+    /// stored alongside it. Shard boundaries depend only on the names around them, so adding or
+    /// removing an entry only changes the shards that contain it.
+    pub fn build(contents: BTreeMap<String, Object>) -> (Directory, Vec<Resource>) {
+        if contents.len() <= SHARD_THRESHOLD {
+            return (Directory::new(contents), Vec::new());
+        }
+
+        let mut resources = Vec::new();
+        let mut shards = Vec::new();
+        let mut leaf = BTreeMap::new();
+        for (name, object) in contents {
+            let boundary = is_shard_boundary(0, &name);
+            leaf.insert(name, object);
+
+            if boundary {
+                let leaf = Directory::new(std::mem::take(&mut leaf));
+                shards.push(Shard::store(leaf, &mut resources));
+            }
+        }
+        if !leaf.is_empty() {
+            shards.push(Shard::store(Directory::new(leaf), &mut resources));
+        }
+
+        // keep adding levels until the top one is small enough to be read in one go
+        let mut level = 1;
+        while shards.len() as u64 > SHARD_FANOUT {
+            let mut parents = Vec::new();
+            let mut group = Vec::new();
+            for shard in shards {
+                let boundary = is_shard_boundary(level, &shard.first);
+                group.push(shard);
+
+                if boundary {
+                    let node = Directory::from_shards(std::mem::take(&mut group));
+                    parents.push(Shard::store(node, &mut resources));
+                }
+            }
+            if !group.is_empty() {
+                parents.push(Shard::store(Directory::from_shards(group), &mut resources));
+            }
+
+            shards = parents;
+            level += 1;
+        }
+
+        (Directory::from_shards(shards), resources)
+    }
+
+    fn from_shards(shards: Vec<Shard>) -> Self {
+        Directory {
+            contents: BTreeMap::new(),
+            shards,
+        }
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        !self.shards.is_empty()
+    }
+
+    /// Returns the number of entries in the directory, including those in shards.
+    pub fn len(&self) -> u64 {
+        if self.is_sharded() {
+            self.shards.iter().map(|shard| shard.count).sum()
+        } else {
+            self.contents.len() as u64
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn add_resource(&self, entry: DirectoryEntry) -> Result<Directory, FilesystemError> {
-        if !self.contents.contains_key(&entry.name) {
+        self.check_unsharded()?;
+        if self.contents.contains_key(&entry.name) {
             return Err(FilesystemError::new(&format!(
                 "resource with name '{}' already exists",
                 entry.name
@@ -41,11 +145,19 @@ impl Directory {
         let mut new_contents = self.contents.clone();
         new_contents.insert(entry.name, entry.file);
 
-        let new_directory = Directory {
-            contents: new_contents,
-        };
+        Ok(Directory::new(new_contents))
+    }
+
+    pub fn remove_resource(&self, name: &str) -> Result<Directory, FilesystemError> {
+        self.check_unsharded()?;
+        let mut new_contents = self.contents.clone();
+        if new_contents.remove(name).is_none() {
+            return Err(FilesystemError::new(&format!(
+                "resource with name '{name}' doesn't exist"
+            )));
+        }
 
-        Ok(new_directory)
+        Ok(Directory::new(new_contents))
     }
 
     pub fn replace_resource(
@@ -53,37 +165,237 @@ impl Directory {
         old_resource: Object,
         new_entry: DirectoryEntry,
     ) -> Result<Directory, FilesystemError> {
+        self.check_unsharded()?;
         let mut new_contents = self.contents.clone();
         new_contents.retain(|_, file| *file != old_resource);
         new_contents.insert(new_entry.name, new_entry.file);
 
-        let new_directory = Directory {
-            contents: new_contents,
-        };
+        Ok(Directory::new(new_contents))
+    }
 
-        Ok(new_directory)
+    /// Sharded directories can only be changed by building them again (see [`Directory::build`]).
+    fn check_unsharded(&self) -> Result<(), FilesystemError> {
+        if self.is_sharded() {
+            return Err(FilesystemError::new(
+                "sharded directories can't be changed in place",
+            ));
+        }
+
+        Ok(())
     }
 
-    pub fn get_children(&self) -> impl Iterator<Item = DirectoryEntry> + '_ {
-        self.contents.iter().map(|(name, file)| DirectoryEntry {
-            name: name.clone(), // TODO: erm
-            file: *file,
-        })
+    /// Returns the shards of the directory if it is sharded, or its entries if it isn't, in order
+    /// of name.
+    pub fn nodes(&self) -> Vec<DirectoryNode> {
+        if self.is_sharded() {
+            self.shards
+                .iter()
+                .cloned()
+                .map(DirectoryNode::Shard)
+                .collect()
+        } else {
+            self.contents
+                .iter()
+                .map(|(name, file)| {
+                    DirectoryNode::Entry(DirectoryEntry {
+                        name: name.clone(),
+                        file: *file,
+                    })
+                })
+                .collect()
+        }
     }
 
-    pub fn get_child(&self, name: &str) -> Result<Object, PathResolutionError> {
+    /// Returns the objects of the shards directly under the directory.
+    pub fn shard_objects(&self) -> impl Iterator<Item = Object> + '_ {
+        self.shards.iter().map(|shard| shard.object)
+    }
+
+    /// Iterates over the entries of the directory in order of name, fetching shards from `store`
+    /// only as they are reached.
+    pub fn get_children<'a, T>(&'a self, store: &'a T) -> Children<'a, T>
+    where
+        T: ContentAddressedStore + ?Sized,
+    {
+        self.get_children_from(store, 0)
+    }
+
+    /// Like [`Directory::get_children`], but starting at the entry at `offset`. Shards that come
+    /// entirely before it aren't fetched.
+    pub fn get_children_from<'a, T>(&'a self, store: &'a T, offset: u64) -> Children<'a, T>
+    where
+        T: ContentAddressedStore + ?Sized,
+    {
+        let mut children = Children {
+            store,
+            stack: Vec::new(),
+            error: None,
+        };
+        children.push(self, offset);
+
+        children
+    }
+
+    /// Looks up a single entry, fetching only the shards on the way to it.
+    pub fn get_child<T>(&self, store: &T, name: &str) -> Result<Object, PathResolutionError>
+    where
+        T: ContentAddressedStore + ?Sized,
+    {
         if name.is_empty() {
             // todo: move this check elsewhere so we don't have to make a copy here
             let res: Resource = Arc::new(self.clone()).into();
             return Ok(Object::from(&res));
         }
 
-        let child = self.contents.get(name);
-        match child {
-            Some(item) => Ok(*item),
-            None => Err(PathResolutionError::new(&format!(
-                "unable to find '{name}'",
-            ))),
+        let not_found = || PathResolutionError::new(&format!("unable to find '{name}'"));
+
+        if self.is_sharded() {
+            // the last shard starting at or before the name is the only one that could hold it
+            let index = self
+                .shards
+                .partition_point(|shard| shard.first.as_str() <= name);
+            let shard = index
+                .checked_sub(1)
+                .map(|index| &self.shards[index])
+                .ok_or_else(not_found)?;
+            return fetch_shard(store, shard)?.get_child(store, name);
+        }
+
+        self.contents.get(name).copied().ok_or_else(not_found)
+    }
+}
+
+impl Shard {
+    /// Adds the resource for a shard to `resources` and returns a reference to it.
+    fn store(directory: Directory, resources: &mut Vec<Resource>) -> Self {
+        let first = match directory.shards.first() {
+            Some(shard) => shard.first.clone(),
+            None => directory
+                .contents
+                .keys()
+                .next()
+                .cloned()
+                .unwrap_or_default(),
+        };
+        let count = directory.len();
+
+        let resource: Resource = Arc::new(directory).into();
+        let object = Object::from(&resource);
+        resources.push(resource);
+
+        Shard {
+            first,
+            count,
+            object,
+        }
+    }
+}
+
+/// Whether a shard at `level` (0 being the one holding entries) ends after the entry or shard
+/// starting with `name`.
+fn is_shard_boundary(level: u32, name: &str) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(level.to_be_bytes());
+    hasher.update(name);
+    let digest = hasher.finalize();
+
+    let value = u64::from_be_bytes(digest[..8].try_into().expect("sha256 hash is 32 bytes"));
+    value % SHARD_FANOUT == 0
+}
+
+fn fetch_shard<T>(store: &T, shard: &Shard) -> Result<Arc<Directory>, PathResolutionError>
+where
+    T: ContentAddressedStore + ?Sized,
+{
+    store
+        .get(&shard.object)
+        .ok_or(PathResolutionError::new(&format!(
+            "unable to find shard starting at '{}'",
+            shard.first
+        )))
+}
+
+/// Iterator over the entries of a possibly sharded directory (see [`Directory::get_children`]).
+///
+/// If a shard can't be found, this yields an error and then stops.
+pub struct Children<'a, T: ContentAddressedStore + ?Sized> {
+    store: &'a T,
+    /// Entries and shards still to be visited at each level, innermost last
+    stack: Vec<vec::IntoIter<DirectoryNode>>,
+    error: Option<PathResolutionError>,
+}
+
+/// An entry of a directory, or a shard holding a range of them.
+pub enum DirectoryNode {
+    Entry(DirectoryEntry),
+    Shard(Shard),
+}
+
+impl<'a, T: ContentAddressedStore + ?Sized> Children<'a, T> {
+    /// Queues the entries of `directory`, skipping the first `offset` of them.
+    fn push(&mut self, directory: &Directory, mut offset: u64) {
+        if !directory.is_sharded() {
+            let entries = directory
+                .contents
+                .iter()
+                .skip(offset as usize)
+                .map(|(name, file)| {
+                    DirectoryNode::Entry(DirectoryEntry {
+                        name: name.clone(),
+                        file: *file,
+                    })
+                })
+                .collect::<Vec<_>>();
+            self.stack.push(entries.into_iter());
+            return;
+        }
+
+        // skip whole shards without fetching them
+        let mut shards = directory.shards.iter().peekable();
+        while let Some(shard) = shards.next_if(|shard| offset >= shard.count) {
+            offset -= shard.count;
+        }
+        let mut shards = shards
+            .cloned()
+            .map(DirectoryNode::Shard)
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        // whatever is left of the offset falls inside the first remaining shard
+        let first = if offset > 0 { shards.next() } else { None };
+        self.stack.push(shards);
+        if let Some(DirectoryNode::Shard(shard)) = first {
+            self.enter(&shard, offset);
+        }
+    }
+
+    fn enter(&mut self, shard: &Shard, offset: u64) {
+        match fetch_shard(self.store, shard) {
+            Ok(directory) => self.push(&directory, offset),
+            Err(err) => {
+                self.stack.clear();
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+impl<'a, T: ContentAddressedStore + ?Sized> Iterator for Children<'a, T> {
+    type Item = Result<DirectoryEntry, PathResolutionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+
+            match self.stack.last_mut()?.next() {
+                Some(DirectoryNode::Entry(entry)) => return Some(Ok(entry)),
+                Some(DirectoryNode::Shard(shard)) => self.enter(&shard, 0),
+                None => {
+                    self.stack.pop();
+                }
+            }
         }
     }
 }
@@ -123,3 +435,101 @@ impl From<Arc<Directory>> for Resource {
         Resource::Directory(val)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::store::fs::LocalStore;
+
+    use super::*;
+
+    fn entries(count: usize) -> BTreeMap<String, Object> {
+        (0..count)
+            .map(|i| (format!("entry-{i:06}"), Object::new([(i % 251) as u8; 32])))
+            .collect()
+    }
+
+    /// Builds a directory and stores its shards, returning it along with its shards' objects.
+    fn build(
+        store: &mut LocalStore,
+        contents: BTreeMap<String, Object>,
+    ) -> (Directory, Vec<Object>) {
+        let (directory, shards) = Directory::build(contents);
+        let objects = shards.iter().map(Object::from).collect();
+        store.add_resources(shards);
+        (directory, objects)
+    }
+
+    #[test]
+    fn only_shards_large_directories() {
+        let (small, shards) = Directory::build(entries(SHARD_THRESHOLD));
+        assert!(!small.is_sharded());
+        assert!(shards.is_empty());
+
+        let (large, shards) = Directory::build(entries(SHARD_THRESHOLD + 1));
+        assert!(large.is_sharded());
+        assert!(!shards.is_empty());
+        assert_eq!(large.len(), SHARD_THRESHOLD as u64 + 1);
+    }
+
+    #[test]
+    fn lists_and_finds_entries_across_shards() {
+        let mut store = LocalStore::new();
+        let contents = entries(5_000);
+        let (directory, _) = build(&mut store, contents.clone());
+
+        let listed = directory
+            .get_children(&store)
+            .map(|entry| entry.map(|entry| (entry.name, entry.file)))
+            .collect::<Result<BTreeMap<_, _>, _>>()
+            .unwrap();
+        assert_eq!(listed, contents);
+
+        for (name, object) in contents.iter().step_by(97) {
+            assert_eq!(directory.get_child(&store, name).unwrap(), *object);
+        }
+        assert!(directory.get_child(&store, "missing").is_err());
+        assert!(directory.get_child(&store, "0-before-everything").is_err());
+
+        // starting part way skips straight to the right entry
+        let names: Vec<_> = contents.keys().cloned().collect();
+        for offset in [0, 1, 255, 256, 2_500, 4_999, 5_000] {
+            let listed: Vec<_> = directory
+                .get_children_from(&store, offset as u64)
+                .map(|entry| entry.unwrap().name)
+                .collect();
+            assert_eq!(listed, names[offset..]);
+        }
+    }
+
+    #[test]
+    fn adding_an_entry_only_changes_the_shards_holding_it() {
+        let mut store = LocalStore::new();
+        let contents = entries(20_000);
+        let (_, before) = build(&mut store, contents.clone());
+
+        let mut changed = contents;
+        changed.insert("entry-010000-new".to_string(), Object::new([1; 32]));
+        let (_, after) = build(&mut store, changed);
+
+        let before: HashSet<_> = before.into_iter().collect();
+        let new_shards = after.iter().filter(|shard| !before.contains(shard)).count();
+        // the leaf holding the entry and, at most, a parent above it
+        assert!(new_shards <= 2, "{new_shards} shards changed");
+    }
+
+    #[test]
+    fn nests_shards_when_there_are_too_many() {
+        let mut store = LocalStore::new();
+        let count = 80_000;
+        // about 300 leaves, more than fit in the top level
+        let (directory, _) = build(&mut store, entries(count));
+
+        assert!(directory.shards.len() as u64 <= SHARD_FANOUT);
+        let first: Arc<Directory> = store.get(&directory.shards[0].object).unwrap();
+        assert!(first.is_sharded());
+        assert_eq!(directory.len(), count as u64);
+        assert_eq!(directory.get_children(&store).count(), count);
+    }
+}
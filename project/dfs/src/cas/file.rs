@@ -1,21 +1,279 @@
-use std::sync::Arc;
+use std::{
+    io::{self, Error},
+    ops::Range,
+    sync::Arc,
+};
 
+use libc::EIO;
 use serde::{Deserialize, Serialize};
 
-use super::{object::Object, resource::Resource};
+use super::{
+    chunk::{Chunk, CHUNK_SIZE, HOLE},
+    object::Object,
+    resource::Resource,
+    ContentAddressedStore,
+};
+
+/// Files up to this size are stored inline by default rather than in a separate chunk.
+pub const DEFAULT_INLINE_SIZE: u64 = 4_096;
+
+/// Files with more chunks than this are split into a tree of index nodes, each of which refers to
+/// at most this many chunks or nodes.
+pub const FILE_FANOUT: usize = 1_024;
 
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub struct File {
+    /// Chunks holding the data, or for index nodes (see [`File::build`]), the files holding
+    /// consecutive parts of it
     pub contents: Vec<Object>,
     pub size: u64,
+    /// Number of levels of index nodes below this one
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub depth: u32,
+    /// Bytes of the file in holes, so that its allocated size is known without reading its index
+    /// nodes
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub holes: u64,
+    /// The data itself, for small files that are stored inline instead of in chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<u8>>,
 }
 
 impl File {
     pub fn new(contents: Vec<Object>, size: u64) -> Self {
-        File { contents, size }
+        File {
+            contents,
+            size,
+            depth: 0,
+            holes: 0,
+            data: None,
+        }
+    }
+
+    /// Creates a small file that holds its data itself, which saves storing and fetching a chunk.
+    pub fn inline(data: Vec<u8>) -> Self {
+        File {
+            contents: Vec::new(),
+            size: data.len() as u64,
+            depth: 0,
+            holes: 0,
+            data: Some(data),
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// Creates a file from its chunks, arranging them into a tree of index nodes if there are
+    /// many of them.
+    ///
+    /// Returns the file along with the index nodes it refers to, which need to be stored alongside
+    /// it. Each index node is itself a file holding a contiguous part of the data, and every node
+    /// but the last at each level is full, so appending to a file only changes the nodes along its
+    /// right edge.
+    pub fn build(chunks: Vec<Object>, size: u64) -> (File, Vec<Resource>) {
+        let mut resources = Vec::new();
+        // bytes in holes under each of the current nodes
+        let mut holes: Vec<u64> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| match *chunk == HOLE {
+                true => CHUNK_SIZE.min(size.saturating_sub(i as u64 * CHUNK_SIZE)),
+                false => 0,
+            })
+            .collect();
+        let mut nodes = chunks;
+        let mut depth = 0;
+        // bytes covered by each of the current nodes
+        let mut span = CHUNK_SIZE;
+
+        while nodes.len() > FILE_FANOUT {
+            let node_span = span * FILE_FANOUT as u64;
+            let parent_holes: Vec<u64> = holes
+                .chunks(FILE_FANOUT)
+                .map(|children| children.iter().sum())
+                .collect();
+            let parents = nodes
+                .chunks(FILE_FANOUT)
+                .zip(&parent_holes)
+                .enumerate()
+                .map(|(i, (children, holes))| {
+                    // a whole node of holes is a bigger hole
+                    if children.len() == FILE_FANOUT && children.iter().all(|c| *c == HOLE) {
+                        return HOLE;
+                    }
+
+                    let start = i as u64 * node_span;
+                    let node = File {
+                        contents: children.to_vec(),
+                        size: node_span.min(size - start),
+                        depth,
+                        holes: *holes,
+                        data: None,
+                    };
+
+                    let resource: Resource = Arc::new(node).into();
+                    let object = Object::from(&resource);
+                    resources.push(resource);
+                    object
+                })
+                .collect();
+
+            nodes = parents;
+            holes = parent_holes;
+            depth += 1;
+            span = node_span;
+        }
+
+        let file = File {
+            contents: nodes,
+            size,
+            depth,
+            holes: holes.iter().sum(),
+            data: None,
+        };
+        (file, resources)
+    }
+
+    /// Returns the number of chunks holding the file's data.
+    pub fn chunk_count(&self) -> u64 {
+        self.size.div_ceil(CHUNK_SIZE)
+    }
+
+    /// Returns the number of bytes of the file that aren't in holes.
+    pub fn allocated_size(&self) -> u64 {
+        self.size - self.holes
+    }
+
+    /// Returns the chunks with indices in `range`, fetching only the index nodes that cover it.
+    ///
+    /// Inline files don't have any chunks.
+    pub fn chunks<T>(&self, store: &T, range: Range<u64>) -> io::Result<Vec<Object>>
+    where
+        T: ContentAddressedStore + ?Sized,
+    {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+
+        if self.depth == 0 {
+            return self
+                .contents
+                .get(range.start as usize..range.end as usize)
+                .map(|chunks| chunks.to_vec())
+                .ok_or(Error::from_raw_os_error(EIO));
+        }
+
+        // chunks covered by each child
+        let span = (FILE_FANOUT as u64).pow(self.depth);
+        let mut chunks = Vec::new();
+        for i in range.start / span..range.end.div_ceil(span) {
+            let child_start = i * span;
+            let start = range.start.max(child_start) - child_start;
+            let end = range.end.min(child_start + span) - child_start;
+
+            let child = self
+                .contents
+                .get(i as usize)
+                .ok_or(Error::from_raw_os_error(EIO))?;
+            if *child == HOLE {
+                chunks.extend((start..end).map(|_| HOLE));
+                continue;
+            }
+
+            let child: Arc<File> = store.get(child).ok_or(Error::from_raw_os_error(EIO))?;
+            chunks.extend(child.chunks(store, start..end)?);
+        }
+
+        Ok(chunks)
+    }
+}
+
+/// Makes a [`File`] out of its chunks as they're read, one at a time.
+///
+/// Files whose only chunk is no bigger than the inline size hold their data themselves, and
+/// chunks of zeros become holes. The chunks that need storing are handed back as they're pushed,
+/// so the builder never holds more than the list of chunk objects.
+pub struct FileBuilder {
+    inline_size: u64,
+    size: u64,
+    chunks: Vec<Object>,
+    /// The data of an inline file, once its only chunk has been pushed
+    inline: Option<Vec<u8>>,
+}
+
+impl FileBuilder {
+    pub fn new(inline_size: u64) -> Self {
+        FileBuilder {
+            inline_size,
+            size: 0,
+            chunks: Vec::new(),
+            inline: None,
+        }
+    }
+
+    /// Adds the next chunk of the file, returning it along with its object if it needs storing.
+    pub fn push(&mut self, chunk: Chunk) -> Option<(Object, Resource)> {
+        let len = chunk.data.len() as u64;
+        if self.is_inline(len) {
+            self.inline = Some(chunk.data);
+            return None;
+        }
+        self.size += len;
+
+        if chunk.is_zero() {
+            self.chunks.push(HOLE);
+            return None;
+        }
+
+        let resource: Resource = Arc::new(chunk).into();
+        let object = Object::from(&resource);
+        self.chunks.push(object);
+        Some((object, resource))
+    }
+
+    /// Adds the next `len` bytes of the file, which are known to be zero without reading them.
+    pub fn push_hole(&mut self, len: u64) {
+        if self.is_inline(len) {
+            self.inline = Some(vec![0; len as usize]);
+            return;
+        }
+
+        self.size += len;
+        self.chunks.push(HOLE);
+    }
+
+    /// Returns the file's object along with the resources left to store, which are its index
+    /// nodes followed by the file itself.
+    pub fn finish(self) -> (Object, Vec<(Object, Resource)>) {
+        let (file, index_nodes) = match self.inline {
+            Some(data) => (File::inline(data), Vec::new()),
+            None => File::build(self.chunks, self.size),
+        };
+
+        let mut resources: Vec<_> = index_nodes
+            .into_iter()
+            .map(|node| (Object::from(&node), node))
+            .collect();
+        let resource: Resource = Arc::new(file).into();
+        let object = Object::from(&resource);
+        resources.push((object, resource));
+
+        (object, resources)
+    }
+
+    /// Returns whether a chunk of `len` bytes is the whole file and small enough to go inline,
+    /// which a short first chunk always is.
+    fn is_inline(&self, len: u64) -> bool {
+        self.chunks.is_empty() && len <= self.inline_size && len < CHUNK_SIZE
     }
 }
 
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
 impl<'a> TryFrom<&'a Resource> for &'a File {
     type Error = ();
 
@@ -45,3 +303,216 @@ impl From<Arc<File>> for Resource {
         Resource::File(val)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::store::fs::LocalStore;
+
+    use super::*;
+
+    fn chunk(i: usize) -> Object {
+        let mut hash = [0xff; 32];
+        hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        Object::new(hash)
+    }
+
+    /// Builds a file of `count` chunks and stores its index nodes.
+    fn build(store: &mut LocalStore, chunks: Vec<Object>) -> (File, Vec<Object>) {
+        let size = chunks.len() as u64 * CHUNK_SIZE - 1;
+        let (file, nodes) = File::build(chunks, size);
+        let objects = nodes.iter().map(Object::from).collect();
+        store.add_resources(nodes);
+        (file, objects)
+    }
+
+    #[test]
+    fn keeps_small_files_flat() {
+        let mut store = LocalStore::new();
+        let chunks: Vec<_> = (0..FILE_FANOUT).map(chunk).collect();
+        let (file, nodes) = build(&mut store, chunks.clone());
+
+        assert_eq!(file.depth, 0);
+        assert!(nodes.is_empty());
+        assert_eq!(file.contents, chunks);
+    }
+
+    #[test]
+    fn splits_large_files_into_full_index_nodes() {
+        let mut store = LocalStore::new();
+        let count = FILE_FANOUT * 2 + 3;
+        let (file, nodes) = build(&mut store, (0..count).map(chunk).collect());
+
+        assert_eq!(file.depth, 1);
+        assert_eq!(file.contents, nodes);
+        assert_eq!(file.chunk_count(), count as u64);
+
+        let node_span = CHUNK_SIZE * FILE_FANOUT as u64;
+        let sizes: Vec<_> = nodes
+            .iter()
+            .map(|node| store.get::<Arc<File>>(node).unwrap())
+            .map(|node| (node.depth, node.contents.len(), node.size))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (0, FILE_FANOUT, node_span),
+                (0, FILE_FANOUT, node_span),
+                (0, 3, file.size - 2 * node_span)
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_chunks_across_index_nodes() {
+        let mut store = LocalStore::new();
+        let count = FILE_FANOUT * 3;
+        let (file, _) = build(&mut store, (0..count).map(chunk).collect());
+
+        for range in [
+            0..1,
+            1_000..1_100,
+            1_023..1_025,
+            2_047..3_072,
+            0..3_072,
+            5..5,
+        ] {
+            let expected: Vec<_> = range.clone().map(chunk).collect();
+            let range = range.start as u64..range.end as u64;
+            assert_eq!(file.chunks(&store, range).unwrap(), expected);
+        }
+        assert!(file.chunks(&store, 3_071..3_073).is_err());
+    }
+
+    #[test]
+    fn collapses_index_nodes_of_holes() {
+        let mut store = LocalStore::new();
+        let mut chunks = vec![HOLE; FILE_FANOUT];
+        chunks.extend((0..FILE_FANOUT).map(chunk));
+        let (file, nodes) = build(&mut store, chunks.clone());
+
+        assert_eq!(file.contents[0], HOLE);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(file.chunks(&store, 0..chunks.len() as u64).unwrap(), chunks);
+    }
+
+    #[test]
+    fn appending_only_changes_the_last_index_node() {
+        let mut store = LocalStore::new();
+        let count = FILE_FANOUT * 3 + 10;
+        let (_, before) = build(&mut store, (0..count).map(chunk).collect());
+        let (_, after) = build(&mut store, (0..count + 5).map(chunk).collect());
+
+        assert_eq!(before[..3], after[..3]);
+        assert_ne!(before[3], after[3]);
+    }
+
+    fn build_from(inline_size: u64, chunks: Vec<Chunk>) -> (File, Vec<(Object, Resource)>) {
+        let mut builder = FileBuilder::new(inline_size);
+        let mut resources: Vec<_> = chunks
+            .into_iter()
+            .filter_map(|chunk| builder.push(chunk))
+            .collect();
+        let (object, rest) = builder.finish();
+        resources.extend(rest);
+
+        let (last, file) = resources.last().unwrap();
+        assert_eq!(*last, object);
+        let file: &File = file.try_into().unwrap();
+        (file.clone(), resources)
+    }
+
+    #[test]
+    fn builds_short_files_inline() {
+        let (file, resources) = build_from(16, vec![Chunk { data: vec![1; 16] }]);
+        assert_eq!(file.data, Some(vec![1; 16]));
+        assert_eq!(resources.len(), 1);
+
+        let (file, resources) = build_from(16, vec![Chunk { data: vec![1; 17] }]);
+        assert!(!file.is_inline());
+        assert_eq!(file.size, 17);
+        assert_eq!(resources.len(), 2);
+
+        let (file, _) = build_from(16, Vec::new());
+        assert_eq!((file.size, file.contents.len()), (0, 0));
+    }
+
+    #[test]
+    fn builds_chunks_of_zeros_as_holes() {
+        let chunks = vec![
+            Chunk {
+                data: vec![0; CHUNK_SIZE as usize],
+            },
+            Chunk { data: vec![1; 10] },
+        ];
+        let (file, resources) = build_from(DEFAULT_INLINE_SIZE, chunks);
+
+        assert_eq!(file.contents[0], HOLE);
+        assert_eq!(file.holes, CHUNK_SIZE);
+        // only the chunk of data and the file itself need storing
+        assert_eq!(resources.len(), 2);
+    }
+
+    #[test]
+    fn builds_unread_holes_like_read_zeros() {
+        let mut builder = FileBuilder::new(DEFAULT_INLINE_SIZE);
+        builder.push_hole(CHUNK_SIZE);
+        builder.push(Chunk { data: vec![1; 10] });
+        let (object, _) = builder.finish();
+
+        let chunks = vec![
+            Chunk {
+                data: vec![0; CHUNK_SIZE as usize],
+            },
+            Chunk { data: vec![1; 10] },
+        ];
+        let (file, _) = build_from(DEFAULT_INLINE_SIZE, chunks);
+        assert_eq!(Object::from(&Resource::from(Arc::new(file))), object);
+
+        let mut builder = FileBuilder::new(DEFAULT_INLINE_SIZE);
+        builder.push_hole(10);
+        let (_, resources) = builder.finish();
+        let file: &File = (&resources[0].1).try_into().unwrap();
+        assert_eq!(file.data, Some(vec![0; 10]));
+    }
+
+    #[test]
+    fn counts_bytes_in_holes() {
+        let (file, _) = File::build(vec![HOLE, chunk(1), HOLE], CHUNK_SIZE * 2 + 5);
+
+        assert_eq!(file.holes, CHUNK_SIZE + 5);
+        assert_eq!(file.allocated_size(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn counts_bytes_in_holes_across_index_nodes() {
+        let mut store = LocalStore::new();
+        let count = FILE_FANOUT * 2 + 2;
+        let mut chunks: Vec<_> = (0..count).map(chunk).collect();
+        // a whole node of holes, a single one in the next node, and a short one at the end
+        chunks[..FILE_FANOUT].fill(HOLE);
+        chunks[FILE_FANOUT + 7] = HOLE;
+        chunks[count - 1] = HOLE;
+        let size = count as u64 * CHUNK_SIZE - 1;
+        let (file, nodes) = File::build(chunks, size);
+        store.add_resources(nodes);
+
+        let holes = (FILE_FANOUT as u64 + 2) * CHUNK_SIZE - 1;
+        assert_eq!(file.holes, holes);
+        assert_eq!(file.allocated_size(), size - holes);
+
+        let node_holes: Vec<_> = file.contents[1..]
+            .iter()
+            .map(|node| store.get::<Arc<File>>(node).unwrap().holes)
+            .collect();
+        assert_eq!(node_holes, [CHUNK_SIZE, CHUNK_SIZE - 1]);
+    }
+
+    #[test]
+    fn leaves_hole_count_out_of_files_without_holes() {
+        let (file, _) = File::build(vec![chunk(1)], 10);
+
+        assert_eq!(file.allocated_size(), 10);
+        let resource: Resource = Arc::new(file).into();
+        assert!(!serde_json::to_string(&resource).unwrap().contains("holes"));
+    }
+}
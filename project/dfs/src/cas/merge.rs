@@ -0,0 +1,385 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+use super::{
+    directory::Directory, error::FilesystemError, object::Object, resource::Resource,
+    ContentAddressedStore,
+};
+
+/// Suffix of the entries holding their side of a conflicting change.
+pub const CONFLICT_SUFFIX: &str = ".theirs";
+
+/// An entry that was changed differently on both sides of a merge.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub base: Option<Object>,
+    pub ours: Option<Object>,
+    pub theirs: Option<Object>,
+    /// Where their version was put next to ours, if both sides still have the entry
+    pub theirs_path: Option<String>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (self.base, self.ours, self.theirs) {
+            (None, _, _) => "both added",
+            (Some(_), None, _) => "deleted by us",
+            (Some(_), _, None) => "deleted by them",
+            _ => "both modified",
+        };
+        write!(f, "{kind}: {}", self.path)?;
+        if let Some(theirs_path) = &self.theirs_path {
+            write!(f, ", theirs kept as {theirs_path}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct MergeResult {
+    /// The merged tree
+    pub root: Object,
+    /// Directories created by the merge, which have to be stored before `root` can be read
+    pub resources: Vec<Resource>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Merges the changes made to `base` in `theirs` into `ours`.
+///
+/// Entries changed on only one side take that side's version, and directories changed on both
+/// sides are merged recursively. Any other entry changed on both sides is a conflict: our version
+/// (or theirs, if we deleted it) is kept, and if both sides still have the entry, theirs is added
+/// next to it with [`CONFLICT_SUFFIX`] appended to its name, followed by a number if that name is
+/// already taken.
+pub fn merge<T: ContentAddressedStore>(
+    store: &T,
+    base: &Object,
+    ours: &Object,
+    theirs: &Object,
+) -> Result<MergeResult, FilesystemError> {
+    let mut tree_merge = TreeMerge {
+        store,
+        resources: Vec::new(),
+        conflicts: Vec::new(),
+    };
+
+    let root = match tree_merge.merge_entry("", Some(*base), Some(*ours), Some(*theirs))? {
+        Merged::Clean(Some(root)) => root,
+        _ => {
+            return Err(FilesystemError::new(
+                "roots can only be merged if they're all directories",
+            ))
+        }
+    };
+
+    Ok(MergeResult {
+        root,
+        resources: tree_merge.resources,
+        conflicts: tree_merge.conflicts,
+    })
+}
+
+enum Merged {
+    Clean(Option<Object>),
+    Conflicted {
+        ours: Option<Object>,
+        theirs: Option<Object>,
+    },
+}
+
+struct TreeMerge<'a, T: ContentAddressedStore> {
+    store: &'a T,
+    resources: Vec<Resource>,
+    conflicts: Vec<Conflict>,
+}
+
+impl<'a, T: ContentAddressedStore> TreeMerge<'a, T> {
+    fn merge_entry(
+        &mut self,
+        path: &str,
+        base: Option<Object>,
+        ours: Option<Object>,
+        theirs: Option<Object>,
+    ) -> Result<Merged, FilesystemError> {
+        if ours == theirs || theirs == base {
+            return Ok(Merged::Clean(ours));
+        }
+        if ours == base {
+            return Ok(Merged::Clean(theirs));
+        }
+
+        let (Some(ours), Some(theirs)) = (ours, theirs) else {
+            return Ok(Merged::Conflicted { ours, theirs });
+        };
+
+        // a directory added on both sides is merged against an empty one
+        let base_dir = match base {
+            Some(base) => self.get_directory(path, &base)?,
+            None => Some(Arc::new(Directory::default())),
+        };
+        let our_dir = self.get_directory(path, &ours)?;
+        let their_dir = self.get_directory(path, &theirs)?;
+
+        match (base_dir, our_dir, their_dir) {
+            (Some(base_dir), Some(our_dir), Some(their_dir)) => {
+                let merged = self.merge_directories(path, &base_dir, &our_dir, &their_dir)?;
+                let (merged, shards) = Directory::build(merged);
+                let resource: Resource = Arc::new(merged).into();
+                let object = Object::from(&resource);
+                self.resources.extend(shards);
+                self.resources.push(resource);
+
+                Ok(Merged::Clean(Some(object)))
+            }
+            _ => Ok(Merged::Conflicted {
+                ours: Some(ours),
+                theirs: Some(theirs),
+            }),
+        }
+    }
+
+    fn merge_directories(
+        &mut self,
+        path: &str,
+        base: &Directory,
+        ours: &Directory,
+        theirs: &Directory,
+    ) -> Result<BTreeMap<String, Object>, FilesystemError> {
+        let store = self.store;
+        let children = |dir: &Directory| {
+            dir.get_children(store)
+                .map(|entry| entry.map(|entry| (entry.name, entry.file)))
+                .collect::<Result<BTreeMap<_, _>, _>>()
+                .map_err(|err| FilesystemError::new(&err.to_string()))
+        };
+        let (base, ours, theirs) = (children(base)?, children(ours)?, children(theirs)?);
+        let names = base
+            .keys()
+            .chain(ours.keys())
+            .chain(theirs.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut merged = ours.clone();
+        // their versions of conflicting entries, which are named once every other entry is known
+        let mut markers = Vec::new();
+        for name in names {
+            let child_path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+
+            let base_child = base.get(&name).copied();
+            let our_child = ours.get(&name).copied();
+            let their_child = theirs.get(&name).copied();
+
+            let (result, marker) =
+                match self.merge_entry(&child_path, base_child, our_child, their_child)? {
+                    Merged::Clean(result) => (result, None),
+                    Merged::Conflicted { ours, theirs } => {
+                        self.conflicts.push(Conflict {
+                            path: child_path,
+                            base: base_child,
+                            ours,
+                            theirs,
+                            theirs_path: None,
+                        });
+
+                        match ours {
+                            Some(_) => (
+                                ours,
+                                theirs.map(|theirs| (self.conflicts.len() - 1, theirs)),
+                            ),
+                            None => (theirs, None),
+                        }
+                    }
+                };
+
+            match result {
+                Some(file) => merged.insert(name.clone(), file),
+                None => merged.remove(&name),
+            };
+
+            if let Some((conflict, file)) = marker {
+                markers.push((name, conflict, file));
+            }
+        }
+
+        for (name, conflict, file) in markers {
+            let marker_name = (0..)
+                .map(|n| match n {
+                    0 => format!("{name}{CONFLICT_SUFFIX}"),
+                    n => format!("{name}{CONFLICT_SUFFIX}.{n}"),
+                })
+                .find(|marker_name| !merged.contains_key(marker_name))
+                .expect("some name should be free");
+            merged.insert(marker_name.clone(), file);
+
+            self.conflicts[conflict].theirs_path = Some(match path {
+                "" => marker_name,
+                path => format!("{path}/{marker_name}"),
+            });
+        }
+
+        Ok(merged)
+    }
+
+    fn get_directory(
+        &self,
+        path: &str,
+        object: &Object,
+    ) -> Result<Option<Arc<Directory>>, FilesystemError> {
+        match self.store.get_resource(object) {
+            Some(Resource::Directory(dir)) => Ok(Some(dir)),
+            Some(_) => Ok(None),
+            None => Err(FilesystemError::new(&format!("unable to find '{path}'"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::fs::LocalStore;
+
+    use super::*;
+
+    fn directory(store: &mut LocalStore, entries: &[(&str, Object)]) -> Object {
+        let entries = entries
+            .iter()
+            .map(|(name, object)| (name.to_string(), *object))
+            .collect();
+        store.create_directory(entries)
+    }
+
+    fn children(store: &LocalStore, object: &Object) -> BTreeMap<String, Object> {
+        let dir: Arc<Directory> = store.get(object).unwrap();
+        dir.get_children(store)
+            .map(|entry| entry.map(|entry| (entry.name, entry.file)))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn merge_into(
+        store: &mut LocalStore,
+        base: Object,
+        ours: Object,
+        theirs: Object,
+    ) -> (BTreeMap<String, Object>, Vec<Conflict>) {
+        let result = merge(store, &base, &ours, &theirs).unwrap();
+        store.add_resources(result.resources);
+        (children(store, &result.root), result.conflicts)
+    }
+
+    #[test]
+    fn takes_changes_from_either_side() {
+        let mut store = LocalStore::new();
+        let (one, two, three) = (
+            store.create_file(b"one"),
+            store.create_file(b"two"),
+            store.create_file(b"three"),
+        );
+        let base = directory(&mut store, &[("a", one), ("b", one), ("gone", one)]);
+        let ours = directory(&mut store, &[("a", two), ("b", one)]);
+        let theirs = directory(
+            &mut store,
+            &[("a", one), ("b", two), ("c", three), ("gone", one)],
+        );
+
+        let (merged, conflicts) = merge_into(&mut store, base, ours, theirs);
+
+        let expected = BTreeMap::from([
+            ("a".to_string(), two),
+            ("b".to_string(), two),
+            ("c".to_string(), three),
+        ]);
+        assert_eq!(merged, expected);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merges_directories_changed_on_both_sides() {
+        let mut store = LocalStore::new();
+        let (one, two) = (store.create_file(b"one"), store.create_file(b"two"));
+        let base_sub = directory(&mut store, &[("x", one), ("y", one)]);
+        let our_sub = directory(&mut store, &[("x", two), ("y", one)]);
+        let their_sub = directory(&mut store, &[("x", one), ("y", two)]);
+        let base = directory(&mut store, &[("sub", base_sub)]);
+        let ours = directory(&mut store, &[("sub", our_sub)]);
+        let theirs = directory(&mut store, &[("sub", their_sub)]);
+
+        let (merged, conflicts) = merge_into(&mut store, base, ours, theirs);
+
+        let sub = children(&store, &merged["sub"]);
+        assert_eq!(sub["x"], two);
+        assert_eq!(sub["y"], two);
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn keeps_both_versions_of_conflicting_files() {
+        let mut store = LocalStore::new();
+        let (one, two, three) = (
+            store.create_file(b"one"),
+            store.create_file(b"two"),
+            store.create_file(b"three"),
+        );
+        let base = directory(&mut store, &[("a", one)]);
+        let ours = directory(&mut store, &[("a", two)]);
+        let theirs = directory(&mut store, &[("a", three)]);
+
+        let (merged, conflicts) = merge_into(&mut store, base, ours, theirs);
+
+        assert_eq!(merged["a"], two);
+        assert_eq!(merged["a.theirs"], three);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, "a");
+        assert_eq!(conflicts[0].theirs_path.as_deref(), Some("a.theirs"));
+        assert_eq!(
+            conflicts[0].to_string(),
+            "both modified: a, theirs kept as a.theirs"
+        );
+    }
+
+    #[test]
+    fn picks_a_free_name_for_their_version() {
+        let mut store = LocalStore::new();
+        let (one, two, three, other) = (
+            store.create_file(b"one"),
+            store.create_file(b"two"),
+            store.create_file(b"three"),
+            store.create_file(b"other"),
+        );
+        let taken = [("a.theirs", other), ("a.theirs.1", other)];
+        let base = directory(&mut store, &[&[("a", one)], &taken[..]].concat());
+        let ours = directory(&mut store, &[&[("a", two)], &taken[..]].concat());
+        let theirs = directory(&mut store, &[&[("a", three)], &taken[..]].concat());
+
+        let (merged, conflicts) = merge_into(&mut store, base, ours, theirs);
+
+        assert_eq!(merged["a"], two);
+        assert_eq!(merged["a.theirs"], other);
+        assert_eq!(merged["a.theirs.1"], other);
+        assert_eq!(merged["a.theirs.2"], three);
+        assert_eq!(conflicts[0].theirs_path.as_deref(), Some("a.theirs.2"));
+    }
+
+    #[test]
+    fn keeps_their_version_of_files_we_deleted() {
+        let mut store = LocalStore::new();
+        let (one, two) = (store.create_file(b"one"), store.create_file(b"two"));
+        let base = directory(&mut store, &[("a", one)]);
+        let ours = directory(&mut store, &[]);
+        let theirs = directory(&mut store, &[("a", two)]);
+
+        let (merged, conflicts) = merge_into(&mut store, base, ours, theirs);
+
+        assert_eq!(merged, BTreeMap::from([("a".to_string(), two)]));
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].theirs_path, None);
+        assert_eq!(conflicts[0].to_string(), "deleted by us: a");
+    }
+}
@@ -1,9 +1,13 @@
 pub mod chunk;
+pub mod commit;
+pub mod diff;
 pub mod directory;
 pub mod error;
 pub mod file;
+pub mod merge;
 pub mod object;
+pub mod reader;
 pub mod resource;
 mod store;
 
-pub use store::ContentAddressedStore;
+pub use store::{ContentAddressedStore, StoreUsage};
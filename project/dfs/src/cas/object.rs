@@ -9,6 +9,10 @@ use sha2::{Digest, Sha256};
 
 use super::resource::Resource;
 
+/// Fewest hex digits of a hash that objects can be looked up by, since shorter prefixes match too
+/// many objects to be useful.
+pub const MIN_PREFIX_LENGTH: usize = 4;
+
 /// A hashed resource.
 #[derive(Serialize, Deserialize)]
 pub struct Object {
@@ -20,6 +24,20 @@ impl Object {
     pub fn new(hash: [u8; 32]) -> Self {
         Object { hash }
     }
+
+    /// Returns whether the hash starts with the hex digits in `prefix`, ignoring case.
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        let hash = self.hash.encode_hex::<String>();
+        hash.len() >= prefix.len() && hash[..prefix.len()].eq_ignore_ascii_case(prefix)
+    }
+
+    /// Returns the lowest hash that starts with the hex digits in `prefix`, or `None` if it isn't
+    /// a prefix of a hash.
+    pub fn lowest_with_prefix(prefix: &str) -> Option<Self> {
+        let mut hash = [0; 32];
+        hex::decode_to_slice(format!("{prefix:0<64}"), &mut hash).ok()?;
+        Some(Object::new(hash))
+    }
 }
 
 impl From<&Resource> for Object {
@@ -85,3 +103,38 @@ impl Debug for Object {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(hex: &str) -> Object {
+        let mut hash = [0; 32];
+        hex::decode_to_slice(format!("{hex:f<64}"), &mut hash).unwrap();
+        Object::new(hash)
+    }
+
+    #[test]
+    fn matches_prefixes_ignoring_case() {
+        let object = object("abcd12");
+
+        assert!(object.has_prefix(""));
+        assert!(object.has_prefix("abcd1"));
+        assert!(object.has_prefix("ABCD12"));
+        assert!(!object.has_prefix("abce"));
+        assert!(object.has_prefix(&object.to_string()));
+        assert!(!object.has_prefix(&format!("{object}0")));
+    }
+
+    #[test]
+    fn finds_the_lowest_hash_with_a_prefix() {
+        assert_eq!(
+            Object::lowest_with_prefix("abc").unwrap().to_string(),
+            format!("{:0<64}", "abc")
+        );
+        assert_eq!(Object::lowest_with_prefix("").unwrap().hash, [0; 32]);
+
+        assert!(Object::lowest_with_prefix("abcg").is_none());
+        assert!(Object::lowest_with_prefix(&"a".repeat(65)).is_none());
+    }
+}
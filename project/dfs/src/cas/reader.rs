@@ -0,0 +1,245 @@
+use std::{
+    io::{self, Error, ErrorKind, Read, Seek, SeekFrom},
+    ops::{Deref, Range},
+    sync::{Arc, OnceLock},
+};
+
+use super::{
+    chunk::{Chunk, CHUNK_SIZE},
+    file::File,
+    ContentAddressedStore,
+};
+
+/// Part of a file's contents, borrowed from the chunk (or inline file) that holds it.
+///
+/// Dereferences to the bytes, so reads don't have to copy whole chunks around.
+#[derive(Clone)]
+pub struct FileSlice {
+    source: Source,
+    range: Range<usize>,
+}
+
+#[derive(Clone)]
+enum Source {
+    Chunk(Arc<Chunk>),
+    Inline(Arc<File>),
+}
+
+impl FileSlice {
+    pub(super) fn chunk(chunk: Arc<Chunk>, range: Range<u64>) -> Self {
+        let end = (range.end as usize).min(chunk.data.len());
+        let start = (range.start as usize).min(end);
+        FileSlice {
+            source: Source::Chunk(chunk),
+            range: start..end,
+        }
+    }
+
+    /// A slice of zeros, for part of a hole.
+    pub(super) fn zeros(range: Range<u64>) -> Self {
+        static ZEROS: OnceLock<Arc<Chunk>> = OnceLock::new();
+        let zeros = ZEROS.get_or_init(|| {
+            Arc::new(Chunk {
+                data: vec![0; CHUNK_SIZE as usize],
+            })
+        });
+        FileSlice::chunk(zeros.clone(), range)
+    }
+
+    pub(super) fn inline(file: Arc<File>, range: Range<u64>) -> Self {
+        let len = file.data.as_ref().map_or(0, Vec::len);
+        let end = (range.end as usize).min(len);
+        let start = (range.start as usize).min(end);
+        FileSlice {
+            source: Source::Inline(file),
+            range: start..end,
+        }
+    }
+}
+
+impl Deref for FileSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let data = match &self.source {
+            Source::Chunk(chunk) => &chunk.data,
+            Source::Inline(file) => file.data.as_deref().unwrap_or_default(),
+        };
+        &data[self.range.clone()]
+    }
+}
+
+/// A [`Read`] + [`Seek`] handle on the contents of a file.
+///
+/// Chunks are fetched from the store as they are reached and copied straight into the caller's
+/// buffer.
+pub struct FileReader<'a, T: ContentAddressedStore + ?Sized> {
+    store: &'a T,
+    file: Arc<File>,
+    position: u64,
+    /// The part of the file most recently fetched, and where it starts
+    current: Option<(u64, FileSlice)>,
+}
+
+impl<'a, T: ContentAddressedStore + ?Sized> FileReader<'a, T> {
+    pub fn new(store: &'a T, file: Arc<File>) -> Self {
+        FileReader {
+            store,
+            file,
+            position: 0,
+            current: None,
+        }
+    }
+
+    /// Returns the bytes from the current position to the end of its chunk.
+    fn fill(&mut self) -> io::Result<&[u8]> {
+        let position = self.position;
+        let fetched = self.current.as_ref().is_some_and(|(start, slice)| {
+            *start <= position && position < start + slice.len() as u64
+        });
+
+        if !fetched {
+            // read up to the next chunk boundary so each chunk is only fetched once
+            let chunk_end = (position / CHUNK_SIZE + 1) * CHUNK_SIZE;
+            let slices =
+                self.store
+                    .read_file_slices(self.file.clone(), position, chunk_end - position)?;
+            self.current = slices.into_iter().next().map(|slice| (position, slice));
+        }
+
+        Ok(match &self.current {
+            Some((start, slice)) => &slice[(position - start) as usize..],
+            None => &[],
+        })
+    }
+}
+
+impl<'a, T: ContentAddressedStore + ?Sized> Read for FileReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.file.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let data = self.fill()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.position += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<'a, T: ContentAddressedStore + ?Sized> Seek for FileReader<'a, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.file.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        ))?;
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cas::file::FileBuilder, store::fs::LocalStore};
+
+    use super::*;
+
+    const TAIL: usize = 10;
+
+    /// Stores a file of a chunk of ones, a chunk-long hole and a few threes, returning it along
+    /// with its contents.
+    fn store_file(store: &mut LocalStore) -> (Arc<File>, Vec<u8>) {
+        let mut builder = FileBuilder::new(0);
+        let ones = builder.push(Chunk {
+            data: vec![1; CHUNK_SIZE as usize],
+        });
+        builder.push_hole(CHUNK_SIZE);
+        let threes = builder.push(Chunk {
+            data: vec![3; TAIL],
+        });
+        store.add_hashed_resources(ones.into_iter().chain(threes));
+        let (object, resources) = builder.finish();
+        store.add_hashed_resources(resources);
+
+        let mut contents = vec![1; CHUNK_SIZE as usize];
+        contents.extend(vec![0; CHUNK_SIZE as usize]);
+        contents.extend(vec![3; TAIL]);
+        (store.get(&object).unwrap(), contents)
+    }
+
+    fn read_at<T: ContentAddressedStore>(
+        reader: &mut FileReader<T>,
+        pos: SeekFrom,
+        len: usize,
+    ) -> Vec<u8> {
+        reader.seek(pos).unwrap();
+        let mut buf = vec![0xff; len];
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_across_chunks_and_holes() {
+        let mut store = LocalStore::new();
+        let (file, contents) = store_file(&mut store);
+
+        let mut read = Vec::new();
+        FileReader::new(&store, file)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == contents);
+    }
+
+    #[test]
+    fn stops_reads_at_chunk_boundaries() {
+        let mut store = LocalStore::new();
+        let (file, _) = store_file(&mut store);
+        let mut reader = FileReader::new(&store, file);
+
+        reader.seek(SeekFrom::Start(CHUNK_SIZE - 2)).unwrap();
+        let mut buf = [0xff; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test]
+    fn seeks_into_chunks_and_holes() {
+        let mut store = LocalStore::new();
+        let (file, _) = store_file(&mut store);
+        let mut reader = FileReader::new(&store, file);
+
+        let end = 2 * CHUNK_SIZE + TAIL as u64;
+        let start = SeekFrom::Start(CHUNK_SIZE - 2);
+        assert_eq!(read_at(&mut reader, start, 4), [1, 1, 0, 0]);
+        let back = SeekFrom::End(-(TAIL as i64) - 2);
+        assert_eq!(read_at(&mut reader, back, 4), [0, 0, 3, 3]);
+        assert_eq!(reader.stream_position().unwrap(), end - TAIL as u64 + 2);
+        // going back into the first chunk fetches it again
+        let current = SeekFrom::Current(-(CHUNK_SIZE as i64) - 4);
+        assert_eq!(read_at(&mut reader, current, 3), [1, 1, 0]);
+
+        assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), end + 5);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-(end as i64) - 6)).is_err());
+    }
+
+    #[test]
+    fn reads_inline_files() {
+        let store = LocalStore::new();
+        let file = Arc::new(File::inline(b"inline".to_vec()));
+        let mut reader = FileReader::new(&store, file);
+
+        assert_eq!(read_at(&mut reader, SeekFrom::Start(2), 4), b"line");
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     io::{self, Error},
     sync::Arc,
 };
@@ -6,19 +7,117 @@ use std::{
 use libc::EIO;
 
 use super::{
-    chunk::{Chunk, CHUNK_SIZE},
-    directory::Directory,
+    chunk::{Chunk, CHUNK_SIZE, HOLE},
+    directory::{Directory, DirectoryEntry},
     error::PathResolutionError,
     file::File,
-    object::Object,
+    object::{Object, MIN_PREFIX_LENGTH},
+    reader::FileSlice,
     resource::Resource,
 };
 
+/// How much a store holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreUsage {
+    /// Number of objects
+    pub objects: u64,
+    /// Bytes of file contents in chunks and inline files
+    pub bytes: u64,
+    /// Bytes that could still be added, if known
+    pub available: Option<u64>,
+}
+
 pub trait ContentAddressedStore {
     fn get_resource(&self, object: &Object) -> Option<Resource>;
     fn has(&self, object: &Object) -> bool;
     fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError>;
 
+    /// Returns how much is held locally, which is nothing for stores that only fetch from others.
+    fn usage(&self) -> StoreUsage {
+        StoreUsage::default()
+    }
+
+    /// Returns the mutable named roots and the objects they currently point to.
+    fn roots(&self) -> BTreeMap<String, Object> {
+        BTreeMap::new()
+    }
+
+    fn get_root(&self, name: &str) -> Option<Object> {
+        self.roots().remove(name)
+    }
+
+    /// Lists named roots followed by accessible objects, named by their hashes.
+    fn top_level_entries(&self) -> Result<Vec<DirectoryEntry>, PathResolutionError> {
+        let roots = self.roots().into_iter().map(|(name, file)| DirectoryEntry {
+            name,
+            file: self.peel(file),
+        });
+        let objects = self
+            .accessible_objects()?
+            .into_iter()
+            .map(|obj| DirectoryEntry {
+                name: obj.to_string(),
+                file: obj,
+            });
+
+        Ok(roots.chain(objects).collect())
+    }
+
+    /// Returns the accessible objects whose hashes start with the hex digits in `prefix`.
+    fn objects_with_prefix(&self, prefix: &str) -> Vec<Object> {
+        let objects = self.accessible_objects().unwrap_or_default();
+        objects
+            .into_iter()
+            .filter(|object| object.has_prefix(prefix))
+            .collect()
+    }
+
+    /// Finds the object that a hash, or a prefix of one that only matches one accessible object,
+    /// refers to.
+    ///
+    /// Full hashes are returned as they are, whether or not the object can be found.
+    fn resolve_hash(&self, name: &str) -> Result<Object, PathResolutionError> {
+        let mut hash = [0; 32];
+        if hex::decode_to_slice(name, &mut hash).is_ok() {
+            return Ok(Object::new(hash));
+        }
+
+        if Object::lowest_with_prefix(name).is_none() {
+            return Err(PathResolutionError::new(&format!(
+                "'{name}' is not a sha256 hash or a prefix of one"
+            )));
+        }
+        if name.len() < MIN_PREFIX_LENGTH {
+            return Err(PathResolutionError::new(&format!(
+                "'{name}' is too short, hash prefixes need at least {MIN_PREFIX_LENGTH} digits"
+            )));
+        }
+
+        match self.objects_with_prefix(name).as_slice() {
+            [object] => Ok(*object),
+            [] => Err(PathResolutionError::new(&format!(
+                "no object's hash starts with '{name}'"
+            ))),
+            candidates => {
+                let shown = candidates.iter().take(4).map(Object::to_string);
+                Err(PathResolutionError::new(&format!(
+                    "'{name}' is ambiguous, it is a prefix of {} hashes: {}{}",
+                    candidates.len(),
+                    shown.collect::<Vec<_>>().join(", "),
+                    if candidates.len() > 4 { ", ..." } else { "" },
+                )))
+            }
+        }
+    }
+
+    /// Returns the tree a commit points to, or the object itself if it isn't a commit.
+    fn peel(&self, object: Object) -> Object {
+        match self.get_resource(&object) {
+            Some(Resource::Commit(commit)) => commit.tree,
+            _ => object,
+        }
+    }
+
     fn get<T>(&self, object: &Object) -> Option<T>
     where
         T: TryFrom<Resource>,
@@ -27,21 +126,50 @@ pub trait ContentAddressedStore {
         resource.try_into().ok()
     }
 
+    /// Reads up to `size` bytes from `offset` into a new buffer.
     fn read_file(&self, file: Arc<File>, offset: u64, size: u64) -> io::Result<Vec<u8>> {
-        let end_location = (offset + size).min(file.size) as usize;
-        let start_chunk = offset.div_floor(CHUNK_SIZE) as usize;
-        let end_chunk = end_location.div_ceil(CHUNK_SIZE as usize);
-        let chunk_contents = file.contents[start_chunk..end_chunk].iter().map(|o| {
-            let chunk: Arc<Chunk> = self.get(o).ok_or(Error::from_raw_os_error(EIO))?;
-            Ok(chunk.data.clone())
-        });
-        let chunk_data = chunk_contents.collect::<Result<Vec<Vec<u8>>, Error>>()?;
-        let chunk_data = chunk_data.iter().flatten().cloned().collect::<Vec<u8>>();
+        let slices = self.read_file_slices(file, offset, size)?;
+        let mut data = Vec::with_capacity(slices.iter().map(|s| s.len()).sum());
+        for slice in &slices {
+            data.extend_from_slice(slice);
+        }
+
+        Ok(data)
+    }
+
+    /// Returns the bytes between `offset` and `offset + size` as slices of the chunks holding
+    /// them, one per chunk, without copying anything.
+    fn read_file_slices(
+        &self,
+        file: Arc<File>,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<Vec<FileSlice>> {
+        let end = offset.saturating_add(size).min(file.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        if file.is_inline() {
+            return Ok(vec![FileSlice::inline(file, offset..end)]);
+        }
+
+        let start_chunk = offset / CHUNK_SIZE;
+        let chunk_objects = file.chunks(self, start_chunk..end.div_ceil(CHUNK_SIZE))?;
+        chunk_objects
+            .iter()
+            .zip(start_chunk..)
+            .map(|(o, i)| {
+                let chunk_start = i * CHUNK_SIZE;
+                let range = offset.max(chunk_start) - chunk_start..end - chunk_start;
+                if *o == HOLE {
+                    return Ok(FileSlice::zeros(range));
+                }
 
-        let vec_start = start_chunk * CHUNK_SIZE as usize;
-        let start_chunk_offset = (offset as usize) - vec_start;
-        let end_offset = end_location - vec_start;
-        Ok(chunk_data[start_chunk_offset..end_offset].to_vec())
+                let chunk: Arc<Chunk> = self.get(o).ok_or(Error::from_raw_os_error(EIO))?;
+                Ok(FileSlice::chunk(chunk, range))
+            })
+            .collect()
     }
 
     fn resolve_path(&self, path: &str) -> Result<Object, PathResolutionError> {
@@ -51,23 +179,38 @@ pub trait ContentAddressedStore {
             return Err(PathResolutionError::new("paths must begin with '/'"));
         }
 
-        let root_hash_hex = path_components
+        let root_name = path_components
             .next()
             .ok_or(PathResolutionError::new("missing root hash"))?;
-        let mut root_hash = [0; 32];
-        hex::decode_to_slice(root_hash_hex, &mut root_hash)
-            .map_err(|_| PathResolutionError::new("root name is not a valid sha256 hash"))?;
-
-        let root_object: Object = Object::new(root_hash);
+        let root_object = match self.get_root(root_name) {
+            // named roots are browsed as their latest tree rather than as a commit
+            Some(object) => self.peel(object),
+            None if Object::lowest_with_prefix(root_name).is_some() => {
+                self.resolve_hash(root_name)?
+            }
+            None => {
+                return Err(PathResolutionError::new(&format!(
+                    "'{root_name}' is neither a sha256 hash nor a named root"
+                )))
+            }
+        };
 
-        let root: Arc<Directory> = self
-            .get(&root_object)
-            .ok_or(PathResolutionError::new("unable to find root"))?;
-        let mut curr_dir = root;
+        let mut curr_dir: Arc<Directory> = match self.get_resource(&root_object) {
+            Some(Resource::Directory(dir)) => dir,
+            Some(Resource::Commit(commit)) => Arc::new(commit.to_directory()),
+            // top-level files can be resolved, but only by themselves
+            Some(Resource::File(_)) if path_components.next().is_none() => return Ok(root_object),
+            _ => return Err(PathResolutionError::new("unable to find root")),
+        };
         let mut curr_item = root_object;
 
         while let Some(component) = path_components.next() {
-            let item = curr_dir.get_child(component)?;
+            // empty components (as in trailing slashes) refer to the current directory
+            if component.is_empty() {
+                continue;
+            }
+
+            let item = curr_dir.get_child(self, component)?;
             let resource = self.get(&item).ok_or(PathResolutionError::new(&format!(
                 "unable to find '{component}'"
             )))?;
@@ -76,6 +219,9 @@ pub trait ContentAddressedStore {
                 Resource::Directory(dir) => {
                     curr_dir = dir;
                 }
+                Resource::Commit(commit) => {
+                    curr_dir = Arc::new(commit.to_directory());
+                }
                 Resource::File(_) => {
                     // this consumes the iterator but that's fine because we return here
                     return match path_components.next() {
@@ -94,3 +240,81 @@ pub trait ContentAddressedStore {
         Ok(curr_item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Holds nothing but lists the given objects, so that prefixes can be made to collide.
+    struct ListedStore(Vec<Object>);
+
+    impl ContentAddressedStore for ListedStore {
+        fn get_resource(&self, _object: &Object) -> Option<Resource> {
+            None
+        }
+
+        fn has(&self, _object: &Object) -> bool {
+            false
+        }
+
+        fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn object(hex: &str) -> Object {
+        let mut hash = [0; 32];
+        hex::decode_to_slice(format!("{hex:0<64}"), &mut hash).unwrap();
+        Object::new(hash)
+    }
+
+    fn store() -> ListedStore {
+        ListedStore(vec![object("abcd01"), object("abcd02"), object("beef")])
+    }
+
+    #[test]
+    fn resolves_unique_prefixes() {
+        let store = store();
+
+        assert_eq!(store.resolve_hash("abcd01").unwrap(), object("abcd01"));
+        assert_eq!(store.resolve_hash("BEEF").unwrap(), object("beef"));
+    }
+
+    #[test]
+    fn takes_full_hashes_as_they_are() {
+        let missing = object("0123");
+        assert_eq!(store().resolve_hash(&missing.to_string()).unwrap(), missing);
+    }
+
+    #[test]
+    fn rejects_ambiguous_and_unknown_prefixes() {
+        let store = store();
+
+        let err = store.resolve_hash("abcd").unwrap_err().to_string();
+        assert!(err.contains("ambiguous"), "{}", err);
+        assert!(err.contains("2 hashes"), "{}", err);
+
+        let err = store.resolve_hash("abce").unwrap_err().to_string();
+        assert!(err.contains("no object"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_short_and_malformed_prefixes() {
+        let store = store();
+
+        let err = store.resolve_hash("bee").unwrap_err().to_string();
+        assert!(err.contains("too short"), "{}", err);
+
+        let err = store.resolve_hash("beefy").unwrap_err().to_string();
+        assert!(err.contains("not a sha256 hash"), "{}", err);
+    }
+
+    #[test]
+    fn resolves_prefixes_as_path_roots() {
+        let err = store().resolve_path("/abcd/file").unwrap_err().to_string();
+        assert!(err.contains("ambiguous"), "{}", err);
+
+        let err = store().resolve_path("/beef/file").unwrap_err().to_string();
+        assert!(err.contains("unable to find root"), "{}", err);
+    }
+}
@@ -1,16 +1,24 @@
 use std::{
-    collections::BTreeMap,
-    io::{self},
+    collections::{BTreeMap, HashSet},
+    io::{self, Read},
     net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    cas::{error::PathResolutionError, object::Object, resource::Resource, ContentAddressedStore},
-    network::{fs::NetworkClient, server::spawn_server},
+    cas::{
+        chunk::Chunk, commit::Commit, directory::DirectoryNode, error::PathResolutionError,
+        file::FileBuilder, object::Object, resource::Resource, ContentAddressedStore, StoreUsage,
+    },
+    network::{
+        fs::{self as network, spawn_keepalive, NetworkClient},
+        server::spawn_server,
+    },
     store::fs::LocalStore,
 };
 
+#[derive(Clone)]
 pub struct Filesystem {
     address: SocketAddr,
     store: Arc<Mutex<LocalStore>>,
@@ -32,20 +40,100 @@ impl Filesystem {
 
     pub fn run(&self) -> io::Result<()> {
         spawn_server(self.store.clone(), self.client.clone(), self.address);
+        spawn_keepalive(self.client.clone());
         Ok(())
     }
 
     pub fn add_peer<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
         println!("addpeer called");
-        let x = self.client.lock().unwrap().add_peer(addr);
+        let x = network::add_peer(&self.client, addr);
         println!("addpeer done");
         x
     }
 
+    /// Replaces the local store with the one saved in `dir` (see [`LocalStore::open`]), keeping
+    /// its inline size.
+    pub fn open_store<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let mut opened = LocalStore::open(dir)?;
+        let mut store = self.store.lock().unwrap();
+        opened.set_inline_size(store.inline_size());
+        *store = opened;
+        Ok(())
+    }
+
+    pub fn inline_size(&self) -> u64 {
+        self.store.lock().unwrap().inline_size()
+    }
+
+    /// Sets the size up to which files are stored inline (see [`LocalStore::set_inline_size`]).
+    pub fn set_inline_size(&self, inline_size: u64) {
+        self.store.lock().unwrap().set_inline_size(inline_size);
+    }
+
+    /// Loads the peers remembered at `path` and keeps saving them there (see
+    /// [`NetworkClient::load_peer_table`]).
+    pub fn load_peer_table(&self, path: PathBuf) -> io::Result<()> {
+        self.client.lock().unwrap().load_peer_table(path)
+    }
+
+    /// Connects to remembered peers and then to the `bootstrap` nodes (see [`network::join`]).
+    pub fn join<A: ToSocketAddrs>(&self, bootstrap: &[A]) -> io::Result<()> {
+        let mut addresses = Vec::new();
+        for addr in bootstrap {
+            addresses.extend(addr.to_socket_addrs()?);
+        }
+
+        network::join(&self.client, &addresses);
+        self.client.lock().unwrap().save_peer_table()
+    }
+
+    /// Adds already-hashed resources to the local store under a single lock.
+    pub fn add_resources(&self, resources: Vec<Resource>) {
+        self.store.lock().unwrap().add_resources(resources);
+    }
+
+    /// Adds resources that have already been hashed (see [`LocalStore::add_hashed_resources`]).
+    pub fn add_hashed_resources(&self, resources: Vec<(Object, Resource)>) {
+        self.store.lock().unwrap().add_hashed_resources(resources);
+    }
+
+    pub fn set_root(&self, name: &str, object: Object) {
+        self.store.lock().unwrap().set_root(name, object);
+    }
+
+    /// Records a new version of the named root `name` (see [`LocalStore::commit`]).
+    pub fn commit(&self, name: &str, commit: Commit) -> Object {
+        self.store.lock().unwrap().commit(name, commit)
+    }
+
+    /// Records a new version of the named root `name` with the parents `commit` already has (see
+    /// [`LocalStore::commit_with_parents`]).
+    pub fn commit_with_parents(&self, name: &str, commit: Commit) -> Object {
+        self.store.lock().unwrap().commit_with_parents(name, commit)
+    }
+
     pub fn create_file(&mut self, contents: &[u8]) -> Object {
         self.store.lock().unwrap().create_file(contents)
     }
 
+    /// Creates a file from a reader (see [`LocalStore::create_file_from_reader`]).
+    ///
+    /// Chunks are read and hashed without holding the store's lock, which the server and the
+    /// mount need too, and only locked to add each one.
+    pub fn create_file_from_reader<R: Read>(&mut self, reader: R) -> io::Result<Object> {
+        let mut builder = FileBuilder::new(self.inline_size());
+        for chunk in Chunk::chunks_from_reader(reader) {
+            if let Some(resource) = builder.push(chunk?) {
+                self.store.lock().unwrap().add_hashed_resources([resource]);
+            }
+        }
+
+        let (object, resources) = builder.finish();
+        self.add_hashed_resources(resources);
+
+        Ok(object)
+    }
+
     pub fn create_directory(&mut self, contents: BTreeMap<String, Object>) -> Object {
         self.store.lock().unwrap().create_directory(contents)
     }
@@ -59,13 +147,25 @@ impl ContentAddressedStore for Filesystem {
             return attempted_resource;
         }
 
-        let attempted_resource: Option<Resource> = self.client.lock().unwrap().get(object);
+        // the client is only locked to find the peers to ask, so that other lookups aren't held
+        // up while this one waits on the network
+        let peers = self.client.lock().unwrap().peers();
+        let fetched = NetworkClient::fetch(&peers, object);
+        self.client.lock().unwrap().evict_failing_peers();
 
-        if let Some(resource) = &attempted_resource {
-            self.store.lock().unwrap().add_resource(resource.clone());
+        let (resource, packed) = fetched?;
+        if Object::from(&resource) != *object {
+            eprintln!("Got a different object while fetching {}", object);
+            return None;
         }
+        // small files come along with their directory so that they don't need fetching one by one
+        let packed = packed_children(&resource, packed);
 
-        attempted_resource
+        let mut store = self.store.lock().unwrap();
+        store.add_hashed_resources([(*object, resource.clone())]);
+        store.add_hashed_resources(packed);
+
+        Some(resource)
     }
 
     fn has(&self, object: &Object) -> bool {
@@ -76,4 +176,96 @@ impl ContentAddressedStore for Filesystem {
     fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError> {
         self.store.accessible_objects()
     }
+
+    fn objects_with_prefix(&self, prefix: &str) -> Vec<Object> {
+        self.store.objects_with_prefix(prefix)
+    }
+
+    fn usage(&self) -> StoreUsage {
+        self.store.usage()
+    }
+
+    fn roots(&self) -> BTreeMap<String, Object> {
+        self.store.roots()
+    }
+
+    fn get_root(&self, name: &str) -> Option<Object> {
+        self.store.get_root(name)
+    }
+}
+
+/// Returns the resources packed with `resource` that are inline files in it, along with their
+/// objects, so that peers can't add anything else to the store that way.
+fn packed_children(resource: &Resource, packed: Vec<Resource>) -> Vec<(Object, Resource)> {
+    let Resource::Directory(dir) = resource else {
+        return Vec::new();
+    };
+    let children: HashSet<Object> = dir
+        .nodes()
+        .into_iter()
+        .filter_map(|node| match node {
+            DirectoryNode::Entry(entry) => Some(entry.file),
+            DirectoryNode::Shard(_) => None,
+        })
+        .collect();
+
+    packed
+        .into_iter()
+        .filter(|resource| matches!(resource, Resource::File(file) if file.is_inline()))
+        .map(|resource| (Object::from(&resource), resource))
+        .filter(|(object, _)| children.contains(object))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cas::{directory::Directory, file::File};
+
+    use super::*;
+
+    fn inline_file(data: &[u8]) -> Resource {
+        Arc::new(File::inline(data.to_vec())).into()
+    }
+
+    #[test]
+    fn keeps_packed_inline_files_in_the_directory() {
+        let (a, b) = (inline_file(b"a"), inline_file(b"b"));
+        let dir: Resource = Arc::new(Directory::new(BTreeMap::from([
+            ("a".to_string(), Object::from(&a)),
+            ("b".to_string(), Object::from(&b)),
+        ])))
+        .into();
+
+        let packed = packed_children(&dir, vec![a.clone(), b.clone()]);
+        assert_eq!(
+            packed
+                .into_iter()
+                .map(|(object, _)| object)
+                .collect::<Vec<_>>(),
+            vec![Object::from(&a), Object::from(&b)]
+        );
+    }
+
+    #[test]
+    fn drops_packed_resources_not_inline_in_the_directory() {
+        let (a, other) = (inline_file(b"a"), inline_file(b"other"));
+        let chunk: Resource = Arc::new(Chunk {
+            data: b"chunk".to_vec(),
+        })
+        .into();
+        let chunked: Resource = Arc::new(File::new(vec![Object::from(&chunk)], 5)).into();
+        let dir: Resource = Arc::new(Directory::new(BTreeMap::from([
+            ("a".to_string(), Object::from(&a)),
+            ("chunk".to_string(), Object::from(&chunk)),
+            ("chunked".to_string(), Object::from(&chunked)),
+        ])))
+        .into();
+
+        let packed = packed_children(&dir, vec![other, chunk, chunked, a.clone()]);
+        assert_eq!(packed.len(), 1);
+        assert_eq!(packed[0].0, Object::from(&a));
+
+        // only directories have anything packed with them
+        assert!(packed_children(&a, vec![a.clone()]).is_empty());
+    }
 }
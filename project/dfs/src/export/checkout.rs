@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Error, ErrorKind, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::cas::{
+    chunk::{Chunk, CHUNK_SIZE, HOLE},
+    directory::Directory,
+    file::File,
+    object::Object,
+    resource::Resource,
+    ContentAddressedStore,
+};
+
+/// Writes the tree at `object` out to `dest`, which must not exist yet.
+///
+/// Commits are checked out as their tree. Nothing that already exists is overwritten.
+pub fn checkout<T: ContentAddressedStore>(
+    store: &T,
+    object: &Object,
+    dest: &Path,
+) -> io::Result<()> {
+    match fetch(store, object)? {
+        Resource::File(file) => write_file(store, file, dest),
+        Resource::Directory(dir) => write_directory(store, &dir, dest),
+        Resource::Commit(commit) => checkout(store, &commit.tree, dest),
+        Resource::Chunk(_) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{object} is a chunk, not a file or directory"),
+        )),
+    }
+}
+
+fn write_directory<T: ContentAddressedStore>(
+    store: &T,
+    dir: &Directory,
+    dest: &Path,
+) -> io::Result<()> {
+    fs::create_dir(dest)?;
+
+    for entry in dir.get_children(store) {
+        let entry = entry.map_err(|err| Error::new(ErrorKind::NotFound, err.to_string()))?;
+        // names come from the network, so don't let them escape the destination
+        if matches!(entry.name.as_str(), "" | "." | "..") || entry.name.contains('/') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("refusing to check out entry named '{}'", entry.name),
+            ));
+        }
+
+        checkout(store, &entry.file, &dest.join(&entry.name))?;
+    }
+
+    Ok(())
+}
+
+fn write_file<T: ContentAddressedStore>(store: &T, file: Arc<File>, dest: &Path) -> io::Result<()> {
+    let mut out = OpenOptions::new().write(true).create_new(true).open(dest)?;
+
+    if let Some(data) = &file.data {
+        return out.write_all(data);
+    }
+
+    // holes are skipped over rather than written, so they stay holes in the new file
+    let chunks = file.chunks(store, 0..file.chunk_count())?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        if *chunk == HOLE {
+            continue;
+        }
+
+        let chunk: Arc<Chunk> = store.get(chunk).ok_or(Error::new(
+            ErrorKind::NotFound,
+            format!("unable to find {chunk}"),
+        ))?;
+        out.write_all_at(&chunk.data, i as u64 * CHUNK_SIZE)?;
+    }
+    out.set_len(file.size)?;
+
+    Ok(())
+}
+
+fn fetch<T: ContentAddressedStore>(store: &T, object: &Object) -> io::Result<Resource> {
+    store.get_resource(object).ok_or(Error::new(
+        ErrorKind::NotFound,
+        format!("unable to find {object}"),
+    ))
+}
@@ -0,0 +1,2 @@
+mod checkout;
+pub use checkout::checkout;
@@ -1,167 +1,627 @@
 use std::{
     collections::HashMap,
-    io::{self, Error},
-    sync::Arc,
+    ffi::OsStr,
+    io::{self, Error, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, UNIX_EPOCH},
 };
 
-use fuser::{FileAttr, FileType, Filesystem as FuseFilesystem};
-use libc::{EIO, EISDIR, ENOENT, ENOTDIR};
+use fuser::{
+    consts::FOPEN_KEEP_CACHE, fuse_forget_one, FileAttr, FileType, Filesystem as FuseFilesystem,
+};
+use libc::{
+    EACCES, EBADF, EIO, EISDIR, ENOENT, ENOTDIR, EROFS, F_OK, O_ACCMODE, O_RDONLY, R_OK, W_OK, X_OK,
+};
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
 use crate::cas::{
     chunk::CHUNK_SIZE,
     directory::{Directory, DirectoryEntry},
+    file::File,
     object::Object,
+    reader::FileSlice,
     resource::Resource,
     ContentAddressedStore,
 };
 
-pub struct MountedFilesystem<'a, T: ContentAddressedStore> {
-    filesystem: &'a T,
-    inode_to_object: HashMap<u64, Object>,
-    object_to_inode: HashMap<Object, u64>,
-    lowest_free_inode: u64,
+use super::{
+    inodes::{InodeTable, Node, ROOT_INODE},
+    options::MountOptions,
+    readahead::{Prefetcher, Readahead},
+    snapshots::{Snapshots, SNAPSHOTS_DIR_NAME},
+};
+
+/// Default number of requests to handle at once.
+///
+/// Most of the time spent on a request is waiting for peers, so this is more than the number of
+/// CPUs.
+pub const DEFAULT_FUSE_THREADS: usize = 16;
+
+/// Serves a store over FUSE.
+///
+/// Requests are handed to a pool of worker threads, which reply once they're done, so a read
+/// that's waiting on the network doesn't hold up anything else.
+pub struct MountedFilesystem<T: ContentAddressedStore + Send + Sync + 'static> {
+    mount: Arc<Mount<T>>,
+    prefetcher: Option<Arc<Prefetcher>>,
+    workers: ThreadPool,
 }
 
-const ROOT_INODE: u64 = 1;
+/// The state shared between the workers handling requests.
+struct Mount<T: ContentAddressedStore> {
+    filesystem: T,
+    inodes: Mutex<InodeTable>,
+    /// Files that are open, by handle
+    handles: Mutex<HashMap<u64, OpenFile>>,
+    next_handle: AtomicU64,
+    /// Latest snapshots listed for each named root, so that the history is only walked again
+    /// when the root changes
+    snapshots: Mutex<HashMap<String, Arc<Snapshots>>>,
+    options: MountOptions,
+}
+
+/// A file that has been opened, so that reads don't need to look it up again.
+struct OpenFile {
+    file: Arc<File>,
+    readahead: Readahead,
+}
+
+/// Unit that `blocks` in file attributes and `statfs` are counted in.
+const BLOCK_SIZE: u64 = 512;
+
+/// Longest name reported by `statfs`. Entry names aren't limited, but most tools expect this.
+const MAX_NAME_LENGTH: u32 = 255;
+
+impl<T: ContentAddressedStore + Send + Sync + 'static> MountedFilesystem<T> {
+    pub fn new(filesystem: T, options: MountOptions) -> io::Result<Self> {
+        let workers = ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+            .map_err(Error::other)?;
 
-impl<'a, T: ContentAddressedStore> MountedFilesystem<'a, T> {
-    pub fn new(filesystem: &'a T) -> Self {
-        Self {
+        let inodes = match &options.root {
+            Some(reference) => InodeTable::with_root(root_node(&filesystem, reference)?),
+            None => InodeTable::new(),
+        };
+        let mount = Mount {
             filesystem,
-            inode_to_object: HashMap::new(),
-            object_to_inode: HashMap::new(),
-            lowest_free_inode: ROOT_INODE + 1,
+            inodes: Mutex::new(inodes),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            snapshots: Mutex::new(HashMap::new()),
+            options,
+        };
+        if !mount.is_top_level(ROOT_INODE) {
+            // the mount point is a directory, so only directories can be mounted on it
+            mount.directory(ROOT_INODE)?;
         }
+
+        Ok(Self {
+            mount: Arc::new(mount),
+            prefetcher: None,
+            workers,
+        })
+    }
+
+    /// Fetches chunks ahead of files that are read sequentially with `prefetcher`.
+    pub fn with_prefetcher(mut self, prefetcher: Prefetcher) -> Self {
+        self.prefetcher = Some(Arc::new(prefetcher));
+        self
+    }
+
+    /// Handles a request on a worker thread.
+    fn spawn<F>(&self, handle: F)
+    where
+        F: FnOnce(&Mount<T>) + Send + 'static,
+    {
+        let mount = self.mount.clone();
+        self.workers.spawn(move || handle(&mount));
+    }
+}
+
+impl<T: ContentAddressedStore> Mount<T> {
+    /// Returns whether `ino` is the directory of every named root and object, which is the root
+    /// of the mount unless something else was mounted there.
+    fn is_top_level(&self, ino: u64) -> bool {
+        ino == ROOT_INODE && self.node(ino).is_none()
     }
 
     fn inode_to_file_attr(&self, ino: u64) -> Result<FileAttr, ()> {
-        if ino == ROOT_INODE {
-            return Ok(file_attr(ino, 4_096, 1, FileType::Directory));
+        if self.is_top_level(ino) || matches!(self.node(ino), Some(Node::Snapshots(_))) {
+            return Ok(self.file_attr(ino, 4_096, 4_096 / BLOCK_SIZE, FileType::Directory));
         }
 
         let resource = self
-            .inode_to_object
-            .get(&ino)
-            .and_then(|obj| self.filesystem.get(obj))
+            .inode_to_object(ino)
+            .and_then(|obj| self.filesystem.get(&obj))
             .ok_or(())?;
 
         let (size, blocks) = match &resource {
-            Resource::File(o) => (o.size, o.contents.len() as u64),
-            Resource::Directory(_) => (4_096, 1),
+            Resource::File(o) => (o.size, o.allocated_size().div_ceil(BLOCK_SIZE)),
+            Resource::Directory(_) | Resource::Commit(_) => (4_096, 4_096 / BLOCK_SIZE),
             Resource::Chunk(_) => return Err(()),
         };
 
-        Ok(file_attr(
+        Ok(self.file_attr(
             ino,
             size,
             blocks,
             match &resource {
                 Resource::File(_) => FileType::RegularFile,
-                Resource::Directory(_) => FileType::Directory,
+                Resource::Directory(_) | Resource::Commit(_) => FileType::Directory,
                 Resource::Chunk(_) => return Err(()),
             },
         ))
     }
 
-    fn get_inode(&mut self, obj: &Object) -> u64 {
-        *self.object_to_inode.entry(*obj).or_insert_with(|| {
-            let ino = self.lowest_free_inode;
-            self.lowest_free_inode += 1;
+    /// Returns the object an inode currently stands for, following named roots to their trees.
+    fn inode_to_object(&self, ino: u64) -> Option<Object> {
+        match self.node(ino)? {
+            Node::Object(object) => Some(object),
+            Node::NamedRoot(name) => Some(self.filesystem.peel(self.filesystem.get_root(&name)?)),
+            Node::Snapshots(_) => None,
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<Node> {
+        self.inodes.lock().unwrap().node(ino)
+    }
 
-            self.inode_to_object.insert(ino, *obj);
+    /// Returns the inode for an entry being listed in a directory.
+    fn entry_inode(&self, parent: u64, name: &str, node: Node) -> u64 {
+        self.inodes.lock().unwrap().entry(parent, name, node)
+    }
 
-            ino
-        })
+    /// Gives the kernel a reference to the inode for `name` in `parent`, returning its attributes
+    /// and how long the entry can be cached for.
+    fn lookup_node(&self, parent: u64, name: &str, node: Node) -> io::Result<(FileAttr, Duration)> {
+        // what named roots point to changes, but everything else is found through hashes
+        let ttl = match (self.node(parent), &node) {
+            (Some(Node::NamedRoot(_)), _) | (_, Node::NamedRoot(_)) => self.options.mutable_ttl,
+            _ => self.options.ttl,
+        };
+        let ino = self.inodes.lock().unwrap().lookup(parent, name, node);
+
+        match self.inode_to_file_attr(ino) {
+            Ok(attr) => Ok((attr, ttl)),
+            Err(()) => {
+                // the kernel won't hear about the inode, so it won't forget it either
+                self.forget(ino, 1);
+                Err(Error::from_raw_os_error(ENOENT))
+            }
+        }
+    }
+
+    /// Returns how long the attributes of an inode can be cached for.
+    fn attr_ttl(&self, ino: u64) -> Duration {
+        match self.node(ino) {
+            Some(Node::Object(_)) | Some(Node::Snapshots(_)) => self.options.ttl,
+            Some(Node::NamedRoot(_)) | None => self.options.mutable_ttl,
+        }
+    }
+
+    fn forget(&self, ino: u64, nlookup: u64) {
+        self.inodes.lock().unwrap().forget(ino, nlookup);
+    }
+
+    fn lookup_by_hash(&self, name: &OsStr) -> io::Result<(FileAttr, Duration)> {
+        let name = name.to_str().ok_or(Error::from_raw_os_error(ENOENT))?;
+        let node = match self.filesystem.get_root(name) {
+            // named roots get inodes of their own so that they can have snapshots
+            Some(_) => Node::NamedRoot(name.to_string()),
+            None => {
+                let object = self
+                    .filesystem
+                    .resolve_hash(name)
+                    .map_err(|_| Error::from_raw_os_error(ENOENT))?;
+                Node::Object(object)
+            }
+        };
+
+        self.lookup_node(ROOT_INODE, name, node)
     }
 
-    fn lookup_by_hash(&mut self, name: &std::ffi::OsStr) -> io::Result<FileAttr> {
+    fn lookup_on_dir(&self, parent: u64, name: &OsStr) -> io::Result<(FileAttr, Duration)> {
         let name = name.to_str().ok_or(Error::from_raw_os_error(ENOENT))?;
-        let mut digest = [0u8; 32];
-        hex::decode_to_slice(name, &mut digest).map_err(|_| Error::from_raw_os_error(ENOENT))?;
-        let obj = Object::new(digest);
-        let inode = self.get_inode(&obj);
 
-        self.inode_to_file_attr(inode)
-            .map_err(|_| Error::from_raw_os_error(ENOENT))
+        let node = match self.node(parent) {
+            Some(Node::Snapshots(root_name)) => {
+                let snapshot = self
+                    .snapshots(&root_name)?
+                    .get(name)
+                    .ok_or(Error::from_raw_os_error(ENOENT))?;
+                Node::Object(snapshot)
+            }
+            Some(Node::NamedRoot(root_name)) if name == SNAPSHOTS_DIR_NAME => {
+                self.directory(parent)?;
+                Node::Snapshots(root_name)
+            }
+            _ => {
+                let child = self
+                    .directory(parent)?
+                    .get_child(&self.filesystem, name)
+                    .map_err(|_| Error::from_raw_os_error(ENOENT))?;
+                Node::Object(child)
+            }
+        };
+
+        self.lookup_node(parent, name, node)
+    }
+
+    /// Adds the entries of a directory other than the root to `reply`, starting at `offset`, until
+    /// it is full.
+    ///
+    /// The top of each named root also gets a virtual snapshots directory as its first entry,
+    /// which hides any real entry with the same name. Only the shards of large directories that
+    /// are needed for the reply are fetched.
+    fn fill_directory(
+        &self,
+        ino: u64,
+        offset: u64,
+        reply: &mut fuser::ReplyDirectory,
+    ) -> io::Result<()> {
+        let root_name = match self.node(ino) {
+            Some(Node::Snapshots(root_name)) => {
+                let snapshots = self.snapshots(&root_name)?;
+                for (i, entry) in snapshots.entries.iter().enumerate().skip(offset as usize) {
+                    let file_type = self
+                        .file_type(&entry.file)
+                        .ok_or(Error::from_raw_os_error(ENOENT))?;
+                    let child_inode = self.entry_inode(ino, &entry.name, Node::Object(entry.file));
+                    if reply.add(child_inode, (i + 1) as i64, file_type, &entry.name) {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+            Some(Node::NamedRoot(root_name)) => Some(root_name),
+            Some(Node::Object(_)) | None => None,
+        };
+
+        let directory = self.directory(ino)?;
+        let virtual_entries = u64::from(root_name.is_some());
+        if let Some(root_name) = root_name {
+            let snapshots_inode =
+                self.entry_inode(ino, SNAPSHOTS_DIR_NAME, Node::Snapshots(root_name));
+            if offset == 0 && reply.add(snapshots_inode, 1, FileType::Directory, SNAPSHOTS_DIR_NAME)
+            {
+                return Ok(());
+            }
+        }
+
+        let start = offset.saturating_sub(virtual_entries);
+        for (i, entry) in directory
+            .get_children_from(&self.filesystem, start)
+            .enumerate()
+        {
+            let entry = entry.map_err(|_| Error::from_raw_os_error(EIO))?;
+            if virtual_entries > 0 && entry.name == SNAPSHOTS_DIR_NAME {
+                continue;
+            }
+
+            let file_type = self
+                .file_type(&entry.file)
+                .ok_or(Error::from_raw_os_error(ENOENT))?;
+            let child_inode = self.entry_inode(ino, &entry.name, Node::Object(entry.file));
+            let next_offset = start + i as u64 + virtual_entries + 1;
+            if reply.add(child_inode, next_offset as i64, file_type, &entry.name) {
+                break;
+            }
+        }
+
+        Ok(())
     }
 
-    fn lookup_on_dir(&mut self, parent: u64, name: &std::ffi::OsStr) -> io::Result<FileAttr> {
-        let parent_entry = self
-            .inode_to_object
-            .get(&parent)
-            .and_then(|obj| self.filesystem.get(obj))
+    /// Returns the directory an inode stands for, presenting commits as their tree and parents.
+    fn directory(&self, ino: u64) -> io::Result<Arc<Directory>> {
+        let resource = self
+            .inode_to_object(ino)
+            .and_then(|obj| self.filesystem.get(&obj))
             .ok_or(Error::from_raw_os_error(ENOENT))?;
 
-        match parent_entry {
+        match resource {
             Resource::File(_) => Err(Error::from_raw_os_error(ENOTDIR)),
             Resource::Chunk(_) => Err(Error::from_raw_os_error(ENOTDIR)),
-            Resource::Directory(directory) => {
-                let name = name.to_str().unwrap().to_string();
-                let child = directory
-                    .get_child(&name)
-                    .map_err(|_| Error::from_raw_os_error(ENOENT))?;
+            Resource::Directory(directory) => Ok(directory),
+            Resource::Commit(commit) => Ok(Arc::new(commit.to_directory())),
+        }
+    }
+
+    fn snapshots(&self, root_name: &str) -> io::Result<Arc<Snapshots>> {
+        let head = self
+            .filesystem
+            .get_root(root_name)
+            .ok_or(Error::from_raw_os_error(ENOENT))?;
+
+        let cached = self.snapshots.lock().unwrap().get(root_name).cloned();
+        if let Some(snapshots) = cached.filter(|snapshots| snapshots.head == head) {
+            return Ok(snapshots);
+        }
+
+        // the history is walked without the lock, since it may have to be fetched
+        let snapshots = Arc::new(Snapshots::new(&self.filesystem, head));
+        self.snapshots
+            .lock()
+            .unwrap()
+            .insert(root_name.to_string(), snapshots.clone());
 
-                let child_inode = self.get_inode(&child);
-                self.inode_to_file_attr(child_inode)
-                    .map_err(|_| Error::from_raw_os_error(ENOENT))
+        Ok(snapshots)
+    }
+
+    /// Adds the named roots and accessible objects to `reply`, starting at `offset`.
+    fn fill_top_level(&self, offset: u64, reply: &mut fuser::ReplyDirectory) -> io::Result<()> {
+        let roots = self.filesystem.roots();
+        let entries = match self.options.hide_hashes {
+            true => roots
+                .iter()
+                .map(|(name, object)| DirectoryEntry {
+                    name: name.clone(),
+                    file: self.filesystem.peel(*object),
+                })
+                .collect(),
+            false => self
+                .filesystem
+                .top_level_entries()
+                .map_err(|_| Error::from_raw_os_error(ENOENT))?,
+        };
+
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            let file_type = self
+                .file_type(&entry.file)
+                .ok_or(Error::from_raw_os_error(ENOENT))?;
+            let node = if roots.contains_key(&entry.name) {
+                Node::NamedRoot(entry.name.clone())
+            } else {
+                Node::Object(entry.file)
+            };
+            let inode = self.entry_inode(ROOT_INODE, &entry.name, node);
+            if reply.add(inode, (i + 1) as i64, file_type, &entry.name) {
+                break;
             }
         }
+
+        Ok(())
+    }
+
+    fn file_type(&self, obj: &Object) -> Option<FileType> {
+        match self.filesystem.get(obj) {
+            Some(Resource::File(_)) => Some(FileType::RegularFile),
+            Some(Resource::Directory(_)) | Some(Resource::Commit(_)) => Some(FileType::Directory),
+            Some(Resource::Chunk(_)) | None => None,
+        }
+    }
+
+    /// Opens a file for reading, returning its handle.
+    fn open(&self, ino: u64, flags: i32) -> io::Result<u64> {
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(Error::from_raw_os_error(EROFS));
+        }
+        if matches!(self.node(ino), Some(Node::Snapshots(_))) {
+            return Err(Error::from_raw_os_error(EISDIR));
+        }
+
+        let item = self
+            .inode_to_object(ino)
+            .and_then(|obj| self.filesystem.get(&obj));
+        let file = match item {
+            Some(Resource::File(file)) => file,
+            Some(Resource::Directory(_)) | Some(Resource::Commit(_)) => {
+                return Err(Error::from_raw_os_error(EISDIR))
+            }
+            None | Some(Resource::Chunk(_)) => return Err(Error::from_raw_os_error(ENOENT)),
+        };
+
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let open_file = OpenFile {
+            file,
+            readahead: Readahead::default(),
+        };
+        self.handles.lock().unwrap().insert(fh, open_file);
+
+        Ok(fh)
+    }
+
+    fn read(
+        &self,
+        fh: u64,
+        offset: u64,
+        size: u64,
+        prefetcher: Option<&Prefetcher>,
+    ) -> io::Result<Vec<FileSlice>> {
+        let (file, chunks) = {
+            let mut handles = self.handles.lock().unwrap();
+            let open_file = handles
+                .get_mut(&fh)
+                .ok_or(Error::from_raw_os_error(EBADF))?;
+            let chunks = prefetcher.map(|prefetcher| {
+                let max_window = prefetcher.max_window;
+                open_file
+                    .readahead
+                    .record(&open_file.file, offset, size, max_window)
+            });
+            (open_file.file.clone(), chunks)
+        };
+
+        let slices = self.filesystem.read_file_slices(file.clone(), offset, size);
+        if let (Some(prefetcher), Some(chunks)) = (prefetcher, chunks) {
+            prefetcher.prefetch(file, chunks);
+        }
+
+        slices
+    }
+
+    fn release(&self, fh: u64) {
+        self.handles.lock().unwrap().remove(&fh);
+    }
+
+    fn opendir(&self, ino: u64) -> io::Result<()> {
+        if self.is_top_level(ino) || matches!(self.node(ino), Some(Node::Snapshots(_))) {
+            return Ok(());
+        }
+
+        self.directory(ino).map(|_| ())
+    }
+
+    /// Checks whether the user `uid` in group `gid` may access an inode as `mask` asks.
+    ///
+    /// Nothing can be written to, and otherwise the owner, group and mode of the inode apply as
+    /// usual.
+    fn access(&self, ino: u64, uid: u32, gid: u32, mask: i32) -> io::Result<()> {
+        let attr = self
+            .inode_to_file_attr(ino)
+            .map_err(|_| Error::from_raw_os_error(ENOENT))?;
+        if mask == F_OK {
+            return Ok(());
+        }
+        if mask & W_OK != 0 {
+            return Err(Error::from_raw_os_error(EROFS));
+        }
+
+        let perm = attr.perm;
+        let allowed = if uid == 0 {
+            // root can read anything, and execute anything that anyone can
+            R_OK | if perm & 0o111 != 0 { X_OK } else { 0 }
+        } else if uid == attr.uid {
+            i32::from(perm >> 6 & 0o7)
+        } else if gid == attr.gid {
+            i32::from(perm >> 3 & 0o7)
+        } else {
+            i32::from(perm & 0o7)
+        };
+
+        match mask & !allowed {
+            0 => Ok(()),
+            _ => Err(Error::from_raw_os_error(EACCES)),
+        }
+    }
+
+    fn file_attr(&self, ino: u64, size: u64, blocks: u64, file_type: FileType) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: file_type,
+            perm: match file_type {
+                FileType::Directory => 0o755,
+                _ => 0o644,
+            },
+            // every path has an inode of its own, so files only ever have one link; for directories
+            // counting subdirectories would mean fetching every child, and 1 tells tools like `find`
+            // that the count isn't known rather than that there are none
+            nlink: 1,
+            uid: self.options.uid,
+            gid: self.options.gid,
+            rdev: 0,
+            blksize: CHUNK_SIZE as u32,
+            flags: 0,
+        }
     }
 }
 
-impl<'a, T: ContentAddressedStore> FuseFilesystem for MountedFilesystem<'a, T> {
+impl<T: ContentAddressedStore + Send + Sync + 'static> FuseFilesystem for MountedFilesystem<T> {
     fn lookup(
         &mut self,
         _req: &fuser::Request<'_>,
         parent: u64,
-        name: &std::ffi::OsStr,
+        name: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
-        let result = if parent == ROOT_INODE {
-            self.lookup_by_hash(name)
-        } else {
-            self.lookup_on_dir(parent, name)
-        };
-        match result {
-            Ok(attr) => reply.entry(&Duration::new(1, 0), &attr, 1),
-            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        let name = name.to_os_string();
+        self.spawn(move |mount| {
+            let result = if mount.is_top_level(parent) {
+                mount.lookup_by_hash(&name)
+            } else {
+                mount.lookup_on_dir(parent, &name)
+            };
+            match result {
+                Ok((attr, ttl)) => reply.entry(&ttl, &attr, 1),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+            }
+        });
+    }
+
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        self.mount.forget(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &fuser::Request<'_>, nodes: &[fuse_forget_one]) {
+        for node in nodes {
+            self.mount.forget(node.nodeid, node.nlookup);
         }
     }
 
     fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
-        match self.inode_to_file_attr(ino) {
-            Ok(attr) => reply.attr(&Duration::new(1, 0), &attr),
+        self.spawn(move |mount| match mount.inode_to_file_attr(ino) {
+            Ok(attr) => reply.attr(&mount.attr_ttl(ino), &attr),
             Err(_) => reply.error(ENOENT),
-        }
+        });
+    }
+
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        self.spawn(move |mount| match mount.open(ino, flags) {
+            // files never change, so whatever the kernel has cached can stay
+            Ok(fh) => reply.opened(fh, FOPEN_KEEP_CACHE),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        });
     }
 
     fn read(
         &mut self,
         _req: &fuser::Request<'_>,
-        ino: u64,
-        _fh: u64,
+        _ino: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
-        let item = self
-            .inode_to_object
-            .get(&ino)
-            .and_then(|obj| self.filesystem.get(obj));
-        match item {
-            Some(Resource::File(file)) => {
-                let res = self.filesystem.read_file(file, offset as u64, size as u64);
-                match res {
-                    Ok(data) => reply.data(&data),
-                    Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        let prefetcher = self.prefetcher.clone();
+        self.spawn(move |mount| {
+            match mount.read(fh, offset as u64, size as u64, prefetcher.as_deref()) {
+                // most reads fall within one chunk, which can be replied with directly
+                Ok(slices) if slices.len() == 1 => reply.data(&slices[0]),
+                Ok(slices) => {
+                    let slices: Vec<&[u8]> = slices.iter().map(|slice| &slice[..]).collect();
+                    reply.data(&slices.concat())
                 }
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
             }
-            Some(Resource::Directory(_)) => reply.error(EISDIR),
-            None | Some(Resource::Chunk(_)) => reply.error(ENOENT),
-        }
+        });
+    }
+
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.mount.release(fh);
+        reply.ok();
+    }
+
+    fn opendir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _flags: i32,
+        reply: fuser::ReplyOpen,
+    ) {
+        self.spawn(move |mount| match mount.opendir(ino) {
+            Ok(()) => reply.opened(0, 0),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        });
     }
 
     fn readdir(
@@ -172,82 +632,60 @@ impl<'a, T: ContentAddressedStore> FuseFilesystem for MountedFilesystem<'a, T> {
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        let entries: Option<Vec<DirectoryEntry>> = if ino == ROOT_INODE {
-            self.filesystem.accessible_objects().ok().map(|objects| {
-                objects
-                    .iter()
-                    .map(|obj| DirectoryEntry {
-                        name: obj.to_string(),
-                        file: *obj,
-                    })
-                    .collect()
-            })
-        } else {
-            let directory: Option<Arc<Directory>> = self
-                .inode_to_object
-                .get(&ino)
-                .and_then(|obj| self.filesystem.get(obj));
-
-            directory.map(|dir| dir.get_children().collect())
-        };
+        self.spawn(move |mount| {
+            let result = if mount.is_top_level(ino) {
+                mount.fill_top_level(offset as u64, &mut reply)
+            } else {
+                mount.fill_directory(ino, offset as u64, &mut reply)
+            };
 
-        if let Some(entries) = entries {
-            for (i, child) in entries.iter().enumerate().skip(offset as usize) {
-                let child_inode = self.object_to_inode.entry(child.file).or_insert_with(|| {
-                    let ino = self.lowest_free_inode;
-                    self.lowest_free_inode += 1;
-
-                    self.inode_to_object.insert(ino, child.file);
-
-                    ino
-                });
-
-                let child_resource = self.filesystem.get(&child.file);
-                let file_type = match child_resource {
-                    Some(Resource::File(_)) => Some(FileType::RegularFile),
-                    Some(Resource::Directory(_)) => Some(FileType::Directory),
-                    Some(Resource::Chunk(_)) | None => None,
-                };
-
-                if let Some(file_type) = file_type {
-                    if reply.add(*child_inode, (i + 1) as i64, file_type, &child.name) {
-                        break;
-                    }
-                } else {
-                    reply.error(ENOENT);
-                    return;
-                }
+            match result {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
             }
+        });
+    }
 
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
-        }
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        self.spawn(move |mount| {
+            let usage = mount.filesystem.usage();
+            let free = usage.available.unwrap_or(0) / BLOCK_SIZE;
+            let used = usage.bytes.div_ceil(BLOCK_SIZE);
+            reply.statfs(
+                used + free,
+                free,
+                free,
+                usage.objects,
+                0,
+                BLOCK_SIZE as u32,
+                MAX_NAME_LENGTH,
+                BLOCK_SIZE as u32,
+            );
+        });
+    }
+
+    fn access(&mut self, req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn(move |mount| match mount.access(ino, uid, gid, mask) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        });
     }
 }
 
-fn file_attr(ino: u64, size: u64, blocks: u64, file_type: FileType) -> FileAttr {
-    FileAttr {
-        ino,
-        size,
-        blocks,
-        atime: UNIX_EPOCH,
-        mtime: UNIX_EPOCH,
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind: file_type,
-        perm: match file_type {
-            FileType::Directory => 0o755,
-            _ => 0o644,
-        },
-        nlink: match file_type {
-            FileType::Directory => 2,
-            _ => 1,
-        },
-        uid: 0,
-        gid: 0,
-        rdev: 0,
-        blksize: CHUNK_SIZE as u32,
-        flags: 0,
+/// Finds what to mount for `reference`, which is a named root, a hash, or a path starting at
+/// either.
+///
+/// Named roots on their own keep following the root as it changes.
+fn root_node<T: ContentAddressedStore>(filesystem: &T, reference: &str) -> io::Result<Node> {
+    let path = reference.trim_start_matches('/');
+    if filesystem.get_root(path).is_some() {
+        return Ok(Node::NamedRoot(path.to_string()));
     }
+
+    let object = filesystem
+        .resolve_path(&format!("/{path}"))
+        .map_err(|err| Error::new(ErrorKind::NotFound, err.to_string()))?;
+
+    Ok(Node::Object(object))
 }
@@ -0,0 +1,230 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::cas::object::Object;
+
+pub const ROOT_INODE: u64 = 1;
+
+/// Most inodes that have been listed by `readdir` but not looked up to keep around.
+///
+/// The kernel never forgets these, since it was never told about them, so they are dropped oldest
+/// first instead.
+const MAX_UNREFERENCED: usize = 65_536;
+
+/// What an inode stands for.
+#[derive(PartialEq, Eq, Clone)]
+pub enum Node {
+    /// An object at a particular path
+    Object(Object),
+    /// A named root, which points to whichever tree the root currently does
+    NamedRoot(String),
+    /// The snapshots directory at the top of a named root
+    Snapshots(String),
+}
+
+struct Inode {
+    node: Node,
+    parent: u64,
+    name: String,
+    /// Number of times the kernel has been given this inode by `lookup` and not forgotten it
+    lookups: u64,
+}
+
+/// The inodes the kernel currently knows about.
+///
+/// Each (parent, name) pair gets an inode of its own, even when several of them point to the same
+/// object, so that tools which compare inode numbers see different files. Inodes are dropped once
+/// the kernel forgets them, and numbers are never reused.
+pub struct InodeTable {
+    inodes: HashMap<u64, Inode>,
+    by_name: HashMap<(u64, String), u64>,
+    /// Inodes that were handed out without a lookup, oldest first
+    unreferenced: VecDeque<u64>,
+    lowest_free_inode: u64,
+}
+
+impl InodeTable {
+    pub fn new() -> Self {
+        Self {
+            inodes: HashMap::new(),
+            by_name: HashMap::new(),
+            unreferenced: VecDeque::new(),
+            lowest_free_inode: ROOT_INODE + 1,
+        }
+    }
+
+    /// Creates a table where the root of the mount stands for `node`.
+    pub fn with_root(node: Node) -> Self {
+        let mut table = Self::new();
+        let root = Inode {
+            node,
+            parent: ROOT_INODE,
+            name: String::new(),
+            lookups: 1,
+        };
+        table.inodes.insert(ROOT_INODE, root);
+
+        table
+    }
+
+    pub fn node(&self, ino: u64) -> Option<Node> {
+        self.inodes.get(&ino).map(|inode| inode.node.clone())
+    }
+
+    /// Returns the inode for `name` in `parent`, for listing it in a directory.
+    ///
+    /// If the name now points somewhere else, it gets a new inode.
+    pub fn entry(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        let key = (parent, name.to_string());
+        if let Some(ino) = self.by_name.get(&key) {
+            if self.inodes.get(ino).is_some_and(|inode| inode.node == node) {
+                return *ino;
+            }
+        }
+
+        let ino = self.lowest_free_inode;
+        self.lowest_free_inode += 1;
+        self.inodes.insert(
+            ino,
+            Inode {
+                node,
+                parent,
+                name: key.1.clone(),
+                lookups: 0,
+            },
+        );
+        self.by_name.insert(key, ino);
+
+        self.unreferenced.push_back(ino);
+        while self.unreferenced.len() > MAX_UNREFERENCED {
+            if let Some(old) = self.unreferenced.pop_front() {
+                if self
+                    .inodes
+                    .get(&old)
+                    .is_some_and(|inode| inode.lookups == 0)
+                {
+                    self.remove(old);
+                }
+            }
+        }
+
+        ino
+    }
+
+    /// Like [`InodeTable::entry`], but also counts a reference from the kernel, which lasts until
+    /// it is forgotten.
+    pub fn lookup(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        let ino = self.entry(parent, name, node);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups += 1;
+        }
+
+        ino
+    }
+
+    /// Drops `nlookup` references to an inode, removing it once there are none left.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        // the root lasts as long as the mount
+        if ino == ROOT_INODE {
+            return;
+        }
+        let Some(inode) = self.inodes.get_mut(&ino) else {
+            return;
+        };
+
+        inode.lookups = inode.lookups.saturating_sub(nlookup);
+        if inode.lookups == 0 {
+            self.remove(ino);
+        }
+    }
+
+    fn remove(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.remove(&ino) {
+            let key = (inode.parent, inode.name);
+            if self.by_name.get(&key) == Some(&ino) {
+                self.by_name.remove(&key);
+            }
+        }
+    }
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(i: u8) -> Node {
+        Node::Object(Object::new([i; 32]))
+    }
+
+    #[test]
+    fn keeps_inodes_until_every_lookup_is_forgotten() {
+        let mut table = InodeTable::with_root(object(0));
+        let a = table.lookup(ROOT_INODE, "a", object(1));
+        assert_eq!(table.lookup(ROOT_INODE, "a", object(1)), a);
+        let b = table.lookup(ROOT_INODE, "b", object(2));
+
+        table.forget(a, 1);
+        assert!(table.node(a) == Some(object(1)));
+
+        // batch_forget forgets each inode in turn
+        for (ino, nlookup) in [(a, 1), (b, 1)] {
+            table.forget(ino, nlookup);
+        }
+        assert!(table.node(a).is_none());
+        assert!(table.node(b).is_none());
+        // a forgotten name gets a new inode, since numbers aren't reused
+        assert!(table.lookup(ROOT_INODE, "a", object(1)) > b);
+
+        table.forget(ROOT_INODE, 1);
+        assert!(table.node(ROOT_INODE) == Some(object(0)));
+    }
+
+    #[test]
+    fn forgets_listed_inodes_that_were_never_looked_up() {
+        let mut table = InodeTable::new();
+        let listed = table.entry(ROOT_INODE, "listed", object(1));
+        table.forget(listed, 1);
+
+        assert!(table.node(listed).is_none());
+    }
+
+    #[test]
+    fn evicts_the_oldest_unreferenced_inodes() {
+        let mut table = InodeTable::new();
+        let looked_up = table.lookup(ROOT_INODE, "looked up", object(1));
+        let first = table.entry(ROOT_INODE, "0", object(2));
+        let second = table.entry(ROOT_INODE, "1", object(2));
+        for i in 2..MAX_UNREFERENCED {
+            table.entry(ROOT_INODE, &i.to_string(), object(2));
+        }
+        assert!(table.node(first).is_some());
+
+        table.entry(ROOT_INODE, "last", object(2));
+        assert!(table.node(looked_up).is_some());
+        assert!(table.node(first).is_none());
+        assert!(table.node(second).is_some());
+    }
+
+    #[test]
+    fn gives_names_pointing_elsewhere_new_inodes() {
+        let mut table = InodeTable::new();
+        let old = table.lookup(ROOT_INODE, "a", object(1));
+        let same = table.entry(2, "a", object(1));
+        assert_ne!(old, same);
+
+        let new = table.lookup(ROOT_INODE, "a", object(2));
+        assert_ne!(new, old);
+        assert!(table.node(new) == Some(object(2)));
+        // the kernel may still use the old inode until it forgets it
+        assert!(table.node(old) == Some(object(1)));
+
+        table.forget(old, 1);
+        assert!(table.node(old).is_none());
+        assert_eq!(table.lookup(ROOT_INODE, "a", object(2)), new);
+    }
+}
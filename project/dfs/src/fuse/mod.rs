@@ -1,2 +1,8 @@
 mod fs;
-pub use fs::MountedFilesystem;
+mod inodes;
+mod options;
+mod readahead;
+mod snapshots;
+pub use fs::{MountedFilesystem, DEFAULT_FUSE_THREADS};
+pub use options::{MountOptions, DEFAULT_MUTABLE_TTL, DEFAULT_TTL};
+pub use readahead::{Prefetcher, Readahead, DEFAULT_READAHEAD};
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use fuser::MountOption;
+
+use super::fs::DEFAULT_FUSE_THREADS;
+
+/// Default for how long the kernel may cache what can't change, which is almost everything.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Default for how long the kernel may cache named roots and the entries directly under them.
+pub const DEFAULT_MUTABLE_TTL: Duration = Duration::from_secs(1);
+
+/// How a store is mounted and presented to the kernel.
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    /// Let every user access the mount, rather than only the one mounting it and root
+    pub allow_other: bool,
+    /// Have the kernel check access against the owner and mode of each file
+    pub default_permissions: bool,
+    /// Unmount when the process exits
+    pub auto_unmount: bool,
+    /// Owner reported for every file
+    pub uid: u32,
+    /// Group reported for every file
+    pub gid: u32,
+    /// How long the kernel may cache attributes and entries of objects, which never change
+    pub ttl: Duration,
+    /// How long the kernel may cache named roots and entries under them, which change whenever
+    /// something is committed to them
+    pub mutable_ttl: Duration,
+    /// Number of requests to handle at once (0 uses one thread per CPU)
+    pub threads: usize,
+    /// Named root, hash or path to mount at the mount point, instead of a directory of every named
+    /// root and object
+    pub root: Option<String>,
+    /// Leave objects out of the listing at the mount point, so that only named roots are listed
+    ///
+    /// Objects can still be looked up by hash.
+    pub hide_hashes: bool,
+}
+
+impl MountOptions {
+    /// Returns the options to pass to `mount`.
+    ///
+    /// The mount is always read-only, since nothing in it can be written to.
+    pub fn fuse_options(&self) -> Vec<MountOption> {
+        let mut options = vec![MountOption::FSName("dfs".to_string()), MountOption::RO];
+        options.push(match self.allow_other {
+            true => MountOption::AllowOther,
+            false => MountOption::AllowRoot,
+        });
+        if self.auto_unmount {
+            options.push(MountOption::AutoUnmount);
+        }
+        if self.default_permissions {
+            options.push(MountOption::DefaultPermissions);
+        }
+
+        options
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            allow_other: false,
+            default_permissions: false,
+            auto_unmount: true,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            ttl: DEFAULT_TTL,
+            mutable_ttl: DEFAULT_MUTABLE_TTL,
+            threads: DEFAULT_FUSE_THREADS,
+            root: None,
+            hide_hashes: false,
+        }
+    }
+}
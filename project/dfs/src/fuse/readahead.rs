@@ -0,0 +1,147 @@
+use std::{
+    io,
+    ops::Range,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::cas::{
+    chunk::{CHUNK_SIZE, HOLE},
+    file::File,
+    ContentAddressedStore,
+};
+
+/// Default for the most chunks fetched ahead of a sequential reader.
+pub const DEFAULT_READAHEAD: u64 = 4;
+
+/// Tracks how a file is being read to decide which chunks to fetch before they're asked for.
+///
+/// The window starts at one chunk and doubles with every sequential read, up to a maximum. Any
+/// other read resets it.
+#[derive(Default)]
+pub struct Readahead {
+    /// Where the next read starts if the file is being read sequentially
+    next_offset: u64,
+    /// Number of chunks to stay ahead by
+    window: u64,
+    /// Index of the first chunk that hasn't been prefetched yet
+    prefetched: u64,
+}
+
+impl Readahead {
+    /// Records a read and returns the indices of the chunks that should be prefetched after it.
+    pub fn record(&mut self, file: &File, offset: u64, size: u64, max_window: u64) -> Range<u64> {
+        let sequential = offset == self.next_offset;
+        self.next_offset = offset + size;
+        if !sequential {
+            self.window = 0;
+            self.prefetched = 0;
+            return 0..0;
+        }
+
+        self.window = (self.window * 2).max(1).min(max_window);
+        let next_chunk = (offset + size).div_ceil(CHUNK_SIZE);
+        let start = next_chunk.max(self.prefetched);
+        let end = (next_chunk + self.window).min(file.chunk_count());
+        self.prefetched = self.prefetched.max(end);
+
+        start..end.max(start)
+    }
+}
+
+/// Fetches chunks into the local store on a background thread.
+pub struct Prefetcher {
+    sender: Sender<(Arc<File>, Range<u64>)>,
+    /// Most chunks to fetch ahead of a sequential reader
+    pub max_window: u64,
+}
+
+impl Prefetcher {
+    pub fn spawn<T>(store: T, max_window: u64) -> Self
+    where
+        T: ContentAddressedStore + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<(Arc<File>, Range<u64>)>();
+        thread::spawn(move || {
+            for (file, chunks) in receiver {
+                if let Err(err) = prefetch(&store, &file, chunks) {
+                    eprintln!("Got error {} while prefetching chunks", err);
+                }
+            }
+        });
+
+        Prefetcher { sender, max_window }
+    }
+
+    /// Queues the chunks of `file` with indices in `chunks` to be fetched.
+    pub fn prefetch(&self, file: Arc<File>, chunks: Range<u64>) {
+        if !chunks.is_empty() {
+            // the thread only stops once we're dropped
+            let _ = self.sender.send((file, chunks));
+        }
+    }
+}
+
+fn prefetch<T: ContentAddressedStore>(
+    store: &T,
+    file: &File,
+    chunks: Range<u64>,
+) -> io::Result<()> {
+    for chunk in file.chunks(store, chunks)? {
+        if chunk != HOLE && !store.has(&chunk) {
+            store.get_resource(&chunk);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(chunks: u64) -> File {
+        File::new(vec![HOLE; chunks as usize], chunks * CHUNK_SIZE)
+    }
+
+    /// Records reads of whole chunks starting at each of `chunks`.
+    fn read_chunks(readahead: &mut Readahead, file: &File, chunks: &[u64]) -> Vec<Range<u64>> {
+        chunks
+            .iter()
+            .map(|chunk| readahead.record(file, chunk * CHUNK_SIZE, CHUNK_SIZE, 4))
+            .collect()
+    }
+
+    #[test]
+    fn doubles_the_window_while_reads_are_sequential() {
+        let file = file(20);
+        let mut readahead = Readahead::default();
+
+        let prefetched = read_chunks(&mut readahead, &file, &[0, 1, 2, 3, 4]);
+        assert_eq!(prefetched, vec![1..2, 2..4, 4..7, 7..8, 8..9]);
+    }
+
+    #[test]
+    fn resets_the_window_on_other_reads() {
+        let file = file(20);
+        let mut readahead = Readahead::default();
+
+        let prefetched = read_chunks(&mut readahead, &file, &[0, 1, 2, 10, 11, 12, 0]);
+        assert_eq!(
+            prefetched,
+            vec![1..2, 2..4, 4..7, 0..0, 12..13, 13..15, 0..0]
+        );
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_file() {
+        let file = file(3);
+        let mut readahead = Readahead::default();
+
+        let prefetched = read_chunks(&mut readahead, &file, &[0, 1, 2]);
+        assert_eq!(prefetched, vec![1..2, 2..3, 3..3]);
+    }
+}
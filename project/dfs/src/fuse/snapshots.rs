@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use crate::cas::{
+    commit::history, directory::DirectoryEntry, object::Object, ContentAddressedStore,
+};
+
+/// Name of the virtual directory at the top of each named root that lists its earlier versions.
+pub const SNAPSHOTS_DIR_NAME: &str = ".snapshots";
+
+/// The snapshots of a named root as of one commit, which never change since commits don't.
+pub struct Snapshots {
+    /// Commit the snapshots were listed from
+    pub head: Object,
+    /// Snapshots, oldest first (see [`snapshots`])
+    pub entries: Vec<DirectoryEntry>,
+    by_name: HashMap<String, Object>,
+}
+
+impl Snapshots {
+    pub fn new<T: ContentAddressedStore>(store: &T, head: Object) -> Self {
+        let entries = snapshots(store, head);
+        let by_name = entries
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.file))
+            .collect();
+
+        Self {
+            head,
+            entries,
+            by_name,
+        }
+    }
+
+    /// Returns the tree of the snapshot called `name`.
+    pub fn get(&self, name: &str) -> Option<Object> {
+        self.by_name.get(name).copied()
+    }
+}
+
+/// Lists the trees in the history of the commit `head`, oldest first, named by the time they were
+/// committed.
+///
+/// Commits made within the same second get a numeric suffix so that every name is unique.
+pub fn snapshots<T: ContentAddressedStore>(store: &T, head: Object) -> Vec<DirectoryEntry> {
+    let mut commits = history(store, head);
+    commits.reverse();
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    commits
+        .into_iter()
+        .map(|(_, commit)| {
+            let time = format_timestamp(commit.timestamp);
+            let count = seen.entry(time.clone()).or_default();
+            *count += 1;
+
+            let name = match *count {
+                1 => time,
+                n => format!("{time}-{n}"),
+            };
+            DirectoryEntry {
+                name,
+                file: commit.tree,
+            }
+        })
+        .collect()
+}
+
+/// Formats seconds since the unix epoch as an ISO 8601 UTC time, like `2023-11-30T17:04:05Z`.
+fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Converts days since the unix epoch into a (year, month, day) date in the proleptic Gregorian
+/// calendar.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{cas::commit::Commit, store::fs::LocalStore};
+
+    use super::*;
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1_701_363_845), "2023-11-30T17:04:05Z");
+    }
+
+    #[test]
+    fn names_snapshots_by_commit_time() {
+        let mut store = LocalStore::new();
+        let mut commit = |contents: &[u8], timestamp| {
+            let file = store.create_file(contents);
+            let tree = store.create_directory(BTreeMap::from([("f".to_string(), file)]));
+            let commit = Commit {
+                timestamp,
+                ..Commit::new(tree, vec![], "test", "")
+            };
+            (tree, store.commit("root", commit))
+        };
+        let (first, _) = commit(b"one", 0);
+        let (second, _) = commit(b"two", 0);
+        let (third, head) = commit(b"three", 60);
+
+        let snapshots = Snapshots::new(&store, head);
+
+        let names: Vec<_> = snapshots.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "1970-01-01T00:00:00Z",
+                "1970-01-01T00:00:00Z-2",
+                "1970-01-01T00:01:00Z"
+            ]
+        );
+        assert_eq!(snapshots.get("1970-01-01T00:00:00Z"), Some(first));
+        assert_eq!(snapshots.get("1970-01-01T00:00:00Z-2"), Some(second));
+        assert_eq!(snapshots.get("1970-01-01T00:01:00Z"), Some(third));
+        assert_eq!(snapshots.get("1970-01-01T00:02:00Z"), None);
+    }
+}
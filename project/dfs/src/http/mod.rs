@@ -0,0 +1,2 @@
+mod server;
+pub use server::{run_gateway, spawn_gateway};
@@ -0,0 +1,334 @@
+use std::{
+    io::{self, Error, Read, Seek, SeekFrom},
+    net::ToSocketAddrs,
+    sync::Arc,
+    thread::spawn,
+};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::cas::{
+    directory::DirectoryEntry, file::File, object::Object, reader::FileReader, resource::Resource,
+    ContentAddressedStore,
+};
+
+/// Characters that are left unescaped in links on index pages.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub fn run_gateway<T, A>(store: T, addr: A) -> Result<(), Error>
+where
+    T: ContentAddressedStore + Send + Sync + 'static,
+    A: ToSocketAddrs,
+{
+    let server = Server::http(addr).map_err(Error::other)?;
+    serve(store, server);
+
+    Ok(())
+}
+
+/// Binds the gateway to `addr` and serves it in the background, so that failing to bind is
+/// reported to the caller.
+pub fn spawn_gateway<T, A>(store: T, addr: A) -> Result<(), Error>
+where
+    T: ContentAddressedStore + Send + Sync + 'static,
+    A: ToSocketAddrs,
+{
+    let server = Server::http(addr).map_err(Error::other)?;
+    spawn(move || serve(store, server));
+
+    Ok(())
+}
+
+fn serve<T>(store: T, server: Server)
+where
+    T: ContentAddressedStore + Send + Sync + 'static,
+{
+    let store = Arc::new(store);
+
+    for request in server.incoming_requests() {
+        let store = store.clone();
+
+        spawn(move || {
+            if let Err(err) = handle_request(&*store, request) {
+                eprintln!("Got error {} in gateway", err);
+            }
+        });
+    }
+}
+
+fn handle_request<T: ContentAddressedStore>(store: &T, request: Request) -> io::Result<()> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        let response = Response::empty(405).with_header(header("Allow", "GET, HEAD"));
+        return request.respond(response);
+    }
+
+    let raw_path = request.url().split(['?', '#']).next().unwrap_or("/");
+    let path = match percent_decode_str(raw_path).decode_utf8() {
+        Ok(path) => path.into_owned(),
+        Err(_) => return respond_error(request, 400, "path is not valid utf-8"),
+    };
+
+    if path == "/" {
+        let entries = match store.top_level_entries() {
+            Ok(entries) => entries,
+            Err(err) => return respond_error(request, 500, &err.to_string()),
+        };
+
+        let page = index_page(store, &path, entries);
+        return request.respond(html_response(page));
+    }
+
+    let object = match store.resolve_path(&path) {
+        Ok(object) => object,
+        Err(err) => return respond_error(request, 404, &err.to_string()),
+    };
+
+    let resource = match store.get_resource(&object) {
+        // commits are browsed as their tree and parents
+        Some(Resource::Commit(commit)) => {
+            Some(Resource::Directory(Arc::new(commit.to_directory())))
+        }
+        resource => resource,
+    };
+
+    match resource {
+        Some(Resource::Directory(dir)) => {
+            if !path.ends_with('/') {
+                let location = format!("{}/", encode_path(&path));
+                let response = Response::empty(301).with_header(header("Location", &location));
+                return request.respond(response);
+            }
+
+            if is_not_modified(&request, &object) {
+                return request.respond(not_modified(&object));
+            }
+
+            let entries = match dir.get_children(store).collect::<Result<Vec<_>, _>>() {
+                Ok(entries) => entries,
+                Err(err) => return respond_error(request, 500, &err.to_string()),
+            };
+
+            let page = index_page(store, &path, entries);
+            request.respond(html_response(page).with_header(etag(&object)))
+        }
+        Some(Resource::File(file)) => serve_file(store, request, &path, object, file),
+        Some(Resource::Chunk(_)) | Some(Resource::Commit(_)) | None => {
+            respond_error(request, 404, &format!("unable to find '{path}'"))
+        }
+    }
+}
+
+fn serve_file<T: ContentAddressedStore>(
+    store: &T,
+    request: Request,
+    path: &str,
+    object: Object,
+    file: Arc<File>,
+) -> io::Result<()> {
+    if is_not_modified(&request, &object) {
+        return request.respond(not_modified(&object));
+    }
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Range"))
+        .and_then(|h| parse_range(h.value.as_str(), file.size));
+
+    let (status, start, end) = match range {
+        None => (200, 0, file.size),
+        Some(Ok((start, end))) => (206, start, end),
+        Some(Err(())) => {
+            let response = Response::empty(416)
+                .with_header(header("Content-Range", &format!("bytes */{}", file.size)));
+            return request.respond(response);
+        }
+    };
+
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let mut headers = vec![
+        header("Content-Type", content_type.essence_str()),
+        header("Accept-Ranges", "bytes"),
+        etag(&object),
+    ];
+    if status == 206 {
+        let content_range = format!("bytes {}-{}/{}", start, end - 1, file.size);
+        headers.push(header("Content-Range", &content_range));
+    }
+
+    let mut body = FileReader::new(store, file);
+    body.seek(SeekFrom::Start(start))?;
+    let body = body.take(end - start);
+    let response = Response::new(
+        StatusCode(status),
+        headers,
+        body,
+        Some((end - start) as usize),
+        None,
+    )
+    // always send a Content-Length so that clients can show progress and HEAD works
+    .with_chunked_threshold(usize::MAX);
+
+    request.respond(response)
+}
+
+/// Parses a `Range` header into a half-open byte range.
+///
+/// Returns `None` if the header should be ignored (it is malformed or asks for multiple ranges)
+/// and `Some(Err(()))` if the range can't be satisfied.
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (size.saturating_sub(suffix), size)
+        }
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            (start, (end + 1).min(size))
+        }
+    };
+
+    if start >= size {
+        return Some(Err(()));
+    }
+
+    Some(Ok((start, end)))
+}
+
+fn index_page<T: ContentAddressedStore>(
+    store: &T,
+    path: &str,
+    entries: Vec<DirectoryEntry>,
+) -> String {
+    let title = escape_html(path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+
+    for entry in entries {
+        let suffix = match store.get_resource(&entry.file) {
+            Some(Resource::Directory(_)) | Some(Resource::Commit(_)) => "/",
+            _ => "",
+        };
+        let href = encode_path(&entry.name);
+        let name = escape_html(&entry.name);
+        page.push_str(&format!(
+            "<li><a href=\"{href}{suffix}\">{name}{suffix}</a></li>\n"
+        ));
+    }
+
+    page.push_str("</ul>\n</body>\n</html>\n");
+    page
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn is_not_modified(request: &Request, object: &Object) -> bool {
+    let tag = format!("\"{object}\"");
+    request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("If-None-Match"))
+        .flat_map(|h| h.value.as_str().split(','))
+        .any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == tag
+        })
+}
+
+fn not_modified(object: &Object) -> Response<io::Empty> {
+    Response::empty(304).with_header(etag(object))
+}
+
+fn html_response(page: String) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_string(page).with_header(header("Content-Type", "text/html; charset=utf-8"))
+}
+
+fn respond_error(request: Request, status: u16, message: &str) -> io::Result<()> {
+    let response = Response::from_string(format!("{message}\n"))
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"));
+    request.respond(response)
+}
+
+fn etag(object: &Object) -> Header {
+    header("ETag", &format!("\"{object}\""))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header should be valid ascii")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 100))));
+        assert_eq!(parse_range(" bytes=10- ", 1000), Some(Ok((10, 1000))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 1000))));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_size() {
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 1000))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 1000))));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-0", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+    }
+}
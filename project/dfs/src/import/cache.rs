@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, Metadata},
+    io::{self, Error, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cas::object::Object, store::fs::write_atomically};
+
+use super::exclude::IGNORE_FILE_NAME;
+
+/// Remembers which object each imported file became, so that unchanged files don't have to be
+/// read and hashed again.
+///
+/// A file is considered unchanged if its size, modification time and inode all match. Entries
+/// are only trusted while their object is still in the store.
+///
+/// Directories are remembered too, but since a directory's metadata doesn't change when
+/// something deeper inside it does, they're only reused when a watcher vouches for them (see
+/// [`ImportCache::invalidate`]) and aren't saved.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct ImportCache {
+    entries: HashMap<PathBuf, CacheEntry>,
+    #[serde(skip)]
+    directories: HashMap<PathBuf, Object>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct CacheEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    inode: u64,
+    object: Object,
+}
+
+impl CacheEntry {
+    fn new(metadata: &Metadata, object: Object) -> Self {
+        CacheEntry {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            inode: metadata.ino(),
+            object,
+        }
+    }
+}
+
+impl ImportCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a cache saved with [`ImportCache::save`], or an empty one if `path` doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = serde_json::to_vec(self).map_err(Error::other)?;
+        write_atomically(path.as_ref(), &contents)
+    }
+
+    /// Returns the object `path` was imported as, if it hasn't changed since.
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<Object> {
+        let entry = self.entries.get(path)?;
+        let current = CacheEntry::new(metadata, entry.object);
+
+        (*entry == current).then_some(entry.object)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, metadata: &Metadata, object: Object) {
+        self.entries.insert(path, CacheEntry::new(metadata, object));
+    }
+
+    pub fn get_directory(&self, path: &Path) -> Option<Object> {
+        self.directories.get(path).copied()
+    }
+
+    pub fn insert_directory(&mut self, path: PathBuf, object: Object) {
+        self.directories.insert(path, object);
+    }
+
+    /// Forgets the directories that may have changed because `path` did, which are the ones
+    /// containing it and, since it may have been replaced by something else entirely, the ones
+    /// inside it.
+    pub fn invalidate(&mut self, path: &Path) {
+        if path.file_name() == Some(OsStr::new(IGNORE_FILE_NAME)) {
+            // an ignore file changes what's imported from everything below it
+            if let Some(parent) = path.parent() {
+                self.directories.retain(|dir, _| !dir.starts_with(parent));
+            }
+        }
+
+        self.directories
+            .retain(|dir, _| !path.starts_with(dir) && !dir.starts_with(path));
+    }
+
+    /// Replaces all entries under `root` with the entries of `other`.
+    pub fn replace_under(&mut self, root: &Path, other: ImportCache) {
+        self.entries.retain(|path, _| !path.starts_with(root));
+        self.directories.retain(|path, _| !path.starts_with(root));
+        self.merge(other);
+    }
+
+    /// Adds the entries of `other`, replacing any that already exist.
+    pub fn merge(&mut self, other: ImportCache) {
+        self.entries.extend(other.entries);
+        self.directories.extend(other.directories);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(directories: &[&str]) -> ImportCache {
+        let mut cache = ImportCache::new();
+        for (i, dir) in directories.iter().enumerate() {
+            cache.insert_directory(PathBuf::from(dir), Object::new([i as u8; 32]));
+        }
+        cache
+    }
+
+    fn directories(cache: &ImportCache) -> Vec<&str> {
+        let mut directories: Vec<_> = cache
+            .directories
+            .keys()
+            .map(|dir| dir.to_str().unwrap())
+            .collect();
+        directories.sort();
+        directories
+    }
+
+    #[test]
+    fn forgets_directories_around_a_change() {
+        let mut cache = cache(&["/r", "/r/a", "/r/a/sub", "/r/a/sub/deep", "/r/ab", "/r/c"]);
+        // `a` could have been replaced by another directory, as with `mv a b; mv c a`
+        cache.invalidate(Path::new("/r/a"));
+
+        assert_eq!(directories(&cache), ["/r/ab", "/r/c"]);
+    }
+
+    #[test]
+    fn forgets_everything_below_a_changed_ignore_file() {
+        let mut cache = cache(&["/r", "/r/a", "/r/a/sub", "/r/c"]);
+        cache.invalidate(&Path::new("/r/a").join(IGNORE_FILE_NAME));
+
+        assert_eq!(directories(&cache), ["/r/c"]);
+    }
+}
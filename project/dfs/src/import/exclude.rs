@@ -0,0 +1,67 @@
+use std::{
+    io::{self, Error},
+    path::Path,
+    sync::Arc,
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+/// Name of the per-directory file listing gitignore-style patterns to leave out of imports.
+pub const IGNORE_FILE_NAME: &str = ".dfsignore";
+
+/// A stack of gitignore-style matchers, from the import root down to the current directory.
+///
+/// Matchers for deeper directories take precedence, so a `.dfsignore` file can re-include (with
+/// `!pattern`) something excluded further up.
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeRules {
+    layers: Vec<Arc<Gitignore>>,
+}
+
+impl ExcludeRules {
+    /// Creates rules from patterns relative to the directory being imported.
+    pub fn from_patterns<P: AsRef<Path>>(root: P, patterns: &[String]) -> io::Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            builder.add_line(None, pattern).map_err(Error::other)?;
+        }
+        let gitignore = builder.build().map_err(Error::other)?;
+
+        Ok(ExcludeRules {
+            layers: vec![Arc::new(gitignore)],
+        })
+    }
+
+    /// Returns the rules that apply inside `dir`, including its ignore file if it has one.
+    pub fn enter_directory(&self, dir: &Path) -> io::Result<Self> {
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        if !ignore_file.is_file() {
+            return Ok(self.clone());
+        }
+
+        // a malformed line only invalidates that line, so keep the rest of the file
+        let (gitignore, err) = Gitignore::new(&ignore_file);
+        if let Some(err) = err {
+            eprintln!("warning: {}: {}", ignore_file.display(), err);
+        }
+
+        let mut rules = self.clone();
+        rules.layers.push(Arc::new(gitignore));
+        Ok(rules)
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        for layer in self.layers.iter().rev() {
+            match layer.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        false
+    }
+}
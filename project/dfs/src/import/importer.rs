@@ -0,0 +1,448 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Error, ErrorKind},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use rayon::{
+    prelude::{IntoParallelIterator, ParallelIterator},
+    ThreadPool, ThreadPoolBuilder,
+};
+
+use crate::{
+    cas::{
+        directory::{Directory, DirectoryEntry},
+        file::FileBuilder,
+        object::Object,
+        resource::Resource,
+        ContentAddressedStore,
+    },
+    dfs::fs::Filesystem,
+};
+
+use super::{
+    cache::ImportCache,
+    exclude::ExcludeRules,
+    sparse::{SparseChunk, SparseChunks},
+};
+
+/// Number of chunk bytes to hold before committing them to the store.
+const BATCH_BYTES: u64 = 64 * 1024 * 1024;
+/// Number of resources to hold before committing them to the store.
+const BATCH_RESOURCES: usize = 4096;
+
+#[derive(Clone, Debug, Default)]
+pub struct ImportOptions {
+    /// Gitignore-style patterns, relative to each imported path, that are left out of the import
+    pub exclude: Vec<String>,
+    /// Number of worker threads to chunk and hash with, or 0 to use one per CPU
+    pub jobs: usize,
+}
+
+/// Imports local files and directories into a [`Filesystem`].
+///
+/// Directories are walked and files are chunked and hashed on a pool of worker threads. New
+/// resources are gathered into a batch shared by the workers, which is committed to the store
+/// whenever it fills up, so that workers rarely contend on the store's lock and memory use doesn't
+/// grow with the size of the tree.
+/// The resulting objects don't depend on the number of workers.
+///
+/// Files that haven't changed according to the [`ImportCache`] are not read again, and
+/// directories whose contents haven't changed reuse their existing objects, so re-importing a
+/// path is mostly a matter of walking it.
+pub struct Importer {
+    filesystem: Filesystem,
+    options: ImportOptions,
+    /// The store's inline size (see [`Filesystem::inline_size`]), read once so that workers
+    /// don't lock the store for it
+    inline_size: u64,
+    pool: ThreadPool,
+    cache: RwLock<ImportCache>,
+}
+
+impl Importer {
+    pub fn new(
+        filesystem: Filesystem,
+        options: ImportOptions,
+        cache: ImportCache,
+    ) -> io::Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(options.jobs)
+            .build()
+            .map_err(Error::other)?;
+
+        Ok(Self {
+            inline_size: filesystem.inline_size(),
+            filesystem,
+            options,
+            pool,
+            cache: RwLock::new(cache),
+        })
+    }
+
+    pub fn options(&self) -> &ImportOptions {
+        &self.options
+    }
+
+    pub fn cache(&self) -> ImportCache {
+        self.cache.read().unwrap().clone()
+    }
+
+    /// Imports `path`, returning `None` if it is neither a file nor a directory.
+    pub fn import(&self, path: &Path) -> io::Result<Option<DirectoryEntry>> {
+        self.walk(path, false)
+    }
+
+    /// Imports `path` again, assuming that nothing but `changed` and the directories containing
+    /// them have been modified since `path` was last imported.
+    ///
+    /// Unlike [`Importer::import`], this doesn't walk directories that haven't changed.
+    pub fn reimport(&self, path: &Path, changed: &[PathBuf]) -> io::Result<Option<DirectoryEntry>> {
+        let mut cache = self.cache.write().unwrap();
+        for changed_path in changed {
+            cache.invalidate(changed_path);
+        }
+        drop(cache);
+
+        self.walk(path, true)
+    }
+
+    fn walk(&self, path: &Path, trust_directories: bool) -> io::Result<Option<DirectoryEntry>> {
+        // cache entries are keyed by absolute path so they survive changes of directory
+        let path = env::current_dir()?.join(path);
+        let rules = ExcludeRules::from_patterns(&path, &self.options.exclude)?;
+
+        let previous = self.cache.read().unwrap();
+        let walk = Walk {
+            previous: &previous,
+            current: Mutex::new(ImportCache::new()),
+            pending: Mutex::new(Batch::default()),
+            trust_directories,
+        };
+        let entry = self.pool.install(|| self.add_entry(&path, &rules, &walk));
+        // whatever was hashed is kept even if the import failed part way
+        self.commit(walk.pending.into_inner().unwrap());
+        let entry = entry?;
+
+        let current = walk.current.into_inner().unwrap();
+        drop(previous);
+
+        let mut cache = self.cache.write().unwrap();
+        if trust_directories {
+            // directories that weren't walked keep their entries
+            cache.merge(current);
+        } else {
+            cache.replace_under(&path, current);
+        }
+
+        Ok(entry)
+    }
+
+    fn add_entry(
+        &self,
+        path: &Path,
+        rules: &ExcludeRules,
+        walk: &Walk,
+    ) -> io::Result<Option<DirectoryEntry>> {
+        let metadata = fs::metadata(path)?;
+        let file_type = metadata.file_type();
+
+        let item = if file_type.is_dir() {
+            let cached = walk
+                .cached_directory(path)
+                .filter(|object| self.filesystem.has(object));
+            match cached {
+                Some(object) => object,
+                None => self.add_directory(path, rules, walk)?,
+            }
+        } else if file_type.is_file() {
+            let cached = walk
+                .previous
+                .get(path, &metadata)
+                .filter(|object| self.filesystem.has(object));
+            let object = match cached {
+                Some(object) => object,
+                None => self.add_file(path, walk)?,
+            };
+
+            walk.current
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), &metadata, object);
+
+            object
+        } else {
+            // fifos, sockets and devices would block or never end, so they're left out
+            eprintln!(
+                "skipping {} (not a regular file or directory)",
+                path.display()
+            );
+            return Ok(None);
+        };
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} doesn't have a valid utf-8 name", path.display()),
+            ))?;
+
+        let entry = DirectoryEntry {
+            name: name.to_string(),
+            file: item,
+        };
+
+        Ok(Some(entry))
+    }
+
+    fn add_directory(&self, path: &Path, rules: &ExcludeRules, walk: &Walk) -> io::Result<Object> {
+        let rules = rules.enter_directory(path)?;
+
+        let children = fs::read_dir(path)?
+            .map(|child| Ok(child?.path()))
+            .filter(|child: &io::Result<PathBuf>| match child {
+                Ok(child) => !rules.is_excluded(child, child.is_dir()),
+                Err(_) => true,
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let children = children
+            .into_par_iter()
+            .map(|child| self.add_entry(&child, &rules, walk))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let child_objs: BTreeMap<_, _> = children
+            .into_iter()
+            .flatten()
+            .map(|entry| (entry.name, entry.file))
+            .collect();
+
+        let (directory, shards) = Directory::build(child_objs);
+        let resource: Resource = Arc::new(directory).into();
+        let object = Object::from(&resource);
+        // a directory whose children are all unchanged hashes to an object we already have
+        if !self.filesystem.has(&object) {
+            let mut batch = Batch::default();
+            for shard in shards {
+                batch.push(shard, 0);
+            }
+            batch.push(resource, 0);
+            self.stage(walk, batch);
+        }
+
+        walk.current
+            .lock()
+            .unwrap()
+            .insert_directory(path.to_path_buf(), object);
+
+        Ok(object)
+    }
+
+    fn add_file(&self, path: &Path, walk: &Walk) -> io::Result<Object> {
+        let mut batch = Batch::default();
+        let mut builder = FileBuilder::new(self.inline_size);
+
+        for chunk in SparseChunks::new(fs::File::open(path)?)? {
+            match chunk? {
+                SparseChunk::Data(chunk) => {
+                    let bytes = chunk.data.len() as u64;
+                    if let Some((object, resource)) = builder.push(chunk) {
+                        batch.push_hashed(object, resource, bytes);
+                        if batch.is_full() {
+                            self.stage(walk, mem::take(&mut batch));
+                        }
+                    }
+                }
+                SparseChunk::Hole(len) => builder.push_hole(len),
+            }
+        }
+
+        let (object, resources) = builder.finish();
+        for (object, resource) in resources {
+            batch.push_hashed(object, resource, 0);
+        }
+        self.stage(walk, batch);
+
+        Ok(object)
+    }
+
+    /// Adds `batch` to the resources waiting to be committed, committing them once there are
+    /// enough.
+    ///
+    /// The pending batch stays locked while it's committed so that resources reach the store in
+    /// the order they were staged, which keeps children ahead of the directories holding them.
+    fn stage(&self, walk: &Walk, batch: Batch) {
+        let mut pending = walk.pending.lock().unwrap();
+        pending.append(batch);
+        if pending.is_full() {
+            self.commit(mem::take(&mut *pending));
+        }
+    }
+
+    fn commit(&self, batch: Batch) {
+        if !batch.resources.is_empty() {
+            self.filesystem.add_hashed_resources(batch.resources);
+        }
+    }
+}
+
+/// The state of a single call to [`Importer::import`].
+struct Walk<'c> {
+    /// The cache as of the start of the import
+    previous: &'c ImportCache,
+    /// Entries for every file and directory seen during the import
+    current: Mutex<ImportCache>,
+    /// Resources that haven't been committed yet
+    pending: Mutex<Batch>,
+    /// Whether directories in the cache are known to be unchanged
+    trust_directories: bool,
+}
+
+impl<'c> Walk<'c> {
+    fn cached_directory(&self, path: &Path) -> Option<Object> {
+        if self.trust_directories {
+            self.previous.get_directory(path)
+        } else {
+            None
+        }
+    }
+}
+
+/// Resources that have been hashed but not yet added to the store.
+#[derive(Default)]
+struct Batch {
+    resources: Vec<(Object, Resource)>,
+    bytes: u64,
+}
+
+impl Batch {
+    fn push(&mut self, resource: Resource, bytes: u64) -> Object {
+        let object = Object::from(&resource);
+        self.push_hashed(object, resource, bytes);
+
+        object
+    }
+
+    fn push_hashed(&mut self, object: Object, resource: Resource, bytes: u64) {
+        self.resources.push((object, resource));
+        self.bytes += bytes;
+    }
+
+    fn append(&mut self, other: Batch) {
+        self.resources.extend(other.resources);
+        self.bytes += other.bytes;
+    }
+
+    fn is_full(&self) -> bool {
+        self.bytes >= BATCH_BYTES || self.resources.len() >= BATCH_RESOURCES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("dfs-importer-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn importer(store: Option<&Path>, cache: ImportCache) -> Importer {
+        importer_with_jobs(store, cache, 2)
+    }
+
+    fn importer_with_jobs(store: Option<&Path>, cache: ImportCache, jobs: usize) -> Importer {
+        let filesystem = Filesystem::new("127.0.0.1:0");
+        if let Some(store) = store {
+            filesystem.open_store(store).unwrap();
+        }
+        filesystem.set_inline_size(16);
+        let options = ImportOptions {
+            jobs,
+            ..Default::default()
+        };
+        Importer::new(filesystem, options, cache).unwrap()
+    }
+
+    #[test]
+    fn imports_the_same_tree_with_any_number_of_workers() {
+        let dir = temp_dir("parallel");
+        for i in 0..20 {
+            let sub = dir.join(format!("dir-{i}/sub-{}", i % 3));
+            fs::create_dir_all(&sub).unwrap();
+            fs::write(sub.join("small"), format!("file {i}")).unwrap();
+            fs::write(sub.join(format!("large-{i}")), vec![i as u8; 1_000 + i]).unwrap();
+        }
+        fs::write(dir.join("top"), "top").unwrap();
+
+        let sequential = importer_with_jobs(None, ImportCache::new(), 1);
+        let parallel = importer_with_jobs(None, ImportCache::new(), 4);
+        let sequential = sequential.import(&dir).unwrap().unwrap();
+        let parallel = parallel.import(&dir).unwrap().unwrap();
+        assert_eq!(sequential.file, parallel.file);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reimporting_into_a_saved_store_skips_unchanged_files() {
+        let dir = temp_dir("reimport");
+        let store = dir.join("store");
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("a"), [b'x'; 100]).unwrap();
+        fs::write(tree.join("sub/b"), "hello").unwrap();
+
+        let first = importer(Some(&store), ImportCache::new());
+        let imported = first.import(&tree).unwrap().unwrap();
+        let cache = first.cache();
+        drop(first);
+
+        // keeping the size, inode and modification time means only reading the file would show
+        // that it changed
+        let a = tree.join("a");
+        let modified = fs::metadata(&a).unwrap().modified().unwrap();
+        fs::write(&a, [b'y'; 100]).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let second = importer(Some(&store), cache.clone());
+        assert_eq!(second.import(&tree).unwrap().unwrap().file, imported.file);
+
+        // without the objects the cache points to, files are read again
+        let unsaved = importer(None, cache);
+        assert_ne!(unsaved.import(&tree).unwrap().unwrap().file, imported.file);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn imports_files_as_the_store_would_create_them() {
+        let dir = temp_dir("inline");
+        let importer = importer(None, ImportCache::new());
+        let mut filesystem = importer.filesystem.clone();
+
+        for size in [0, 16, 17, 4_096, 5_000] {
+            let path = dir.join(size.to_string());
+            fs::write(&path, vec![b'x'; size]).unwrap();
+
+            let imported = importer.import(&path).unwrap().unwrap().file;
+            let created = filesystem.create_file(&vec![b'x'; size]);
+            assert_eq!(imported, created, "{} byte file", size);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+mod cache;
+mod exclude;
+mod importer;
+mod sparse;
+mod watch;
+
+pub use cache::ImportCache;
+pub use exclude::ExcludeRules;
+pub use importer::{ImportOptions, Importer};
+pub use sparse::{SparseChunk, SparseChunks};
+pub use watch::{spawn_watcher, watch};
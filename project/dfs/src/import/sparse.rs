@@ -0,0 +1,170 @@
+use std::{
+    fs,
+    io::{self, Error},
+    ops::Range,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+};
+
+use libc::{off_t, ENXIO, SEEK_DATA, SEEK_HOLE};
+
+use crate::cas::chunk::{Chunk, CHUNK_SIZE};
+
+/// A chunk-sized piece of a file, which is either data or entirely zero.
+pub enum SparseChunk {
+    Data(Chunk),
+    /// This many zero bytes
+    Hole(u64),
+}
+
+/// Splits a file into chunks like [`Chunk::chunks_from_reader`], but without reading the parts
+/// of it that the filesystem says are holes.
+///
+/// Chunks that are read but turn out to only hold zeros are also returned as holes.
+pub struct SparseChunks {
+    file: fs::File,
+    size: u64,
+    offset: u64,
+    /// Sorted byte ranges that may hold data
+    data: Vec<Range<u64>>,
+}
+
+impl SparseChunks {
+    pub fn new(file: fs::File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        let data = data_segments(&file, size)?;
+
+        Ok(SparseChunks {
+            file,
+            size,
+            offset: 0,
+            data,
+        })
+    }
+
+    fn has_data(&self, range: &Range<u64>) -> bool {
+        let i = self
+            .data
+            .partition_point(|segment| segment.end <= range.start);
+        self.data
+            .get(i)
+            .is_some_and(|segment| segment.start < range.end)
+    }
+}
+
+impl Iterator for SparseChunks {
+    type Item = io::Result<SparseChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.size {
+            return None;
+        }
+
+        let range = self.offset..(self.offset + CHUNK_SIZE).min(self.size);
+        let len = range.end - range.start;
+        self.offset = range.end;
+
+        if !self.has_data(&range) {
+            return Some(Ok(SparseChunk::Hole(len)));
+        }
+
+        let mut data = vec![0; len as usize];
+        if let Err(err) = self.file.read_exact_at(&mut data, range.start) {
+            return Some(Err(err));
+        }
+
+        let chunk = Chunk { data };
+        if chunk.is_zero() {
+            Some(Ok(SparseChunk::Hole(len)))
+        } else {
+            Some(Ok(SparseChunk::Data(chunk)))
+        }
+    }
+}
+
+/// Finds the parts of a file that hold data with `SEEK_DATA`/`SEEK_HOLE`.
+///
+/// Filesystems that don't support them are treated as if the whole file is data.
+fn data_segments(file: &fs::File, size: u64) -> io::Result<Vec<Range<u64>>> {
+    let whole_file = 0..size;
+    let fd = file.as_raw_fd();
+    let mut segments = Vec::new();
+
+    let mut offset = 0;
+    while offset < size {
+        let start = unsafe { libc::lseek(fd, offset as off_t, SEEK_DATA) };
+        if start < 0 {
+            return match Error::last_os_error().raw_os_error() {
+                // there's no data after `offset`
+                Some(ENXIO) => Ok(segments),
+                _ => Ok(vec![whole_file]),
+            };
+        }
+
+        let end = unsafe { libc::lseek(fd, start, SEEK_HOLE) };
+        if end < 0 {
+            return Ok(vec![whole_file]);
+        }
+
+        segments.push(start as u64..end as u64);
+        offset = end as u64;
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process};
+
+    use super::*;
+
+    fn sparse_file(name: &str) -> (PathBuf, fs::File) {
+        let path = env::temp_dir().join(format!("dfs-sparse-{}-{}", name, process::id()));
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(CHUNK_SIZE * 3 + 100).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn finds_data_segments() {
+        let (path, file) = sparse_file("segments");
+        file.write_all_at(b"data", CHUNK_SIZE + 10).unwrap();
+
+        let segments = data_segments(&file, CHUNK_SIZE * 3 + 100).unwrap();
+        let data = CHUNK_SIZE + 10..CHUNK_SIZE + 14;
+        assert!(segments
+            .iter()
+            .any(|segment| segment.start <= data.start && segment.end >= data.end));
+        assert!(segments.windows(2).all(|pair| pair[0].end <= pair[1].start));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn splits_sparse_files_into_holes_and_data() {
+        let (path, file) = sparse_file("chunks");
+        file.write_all_at(b"data", CHUNK_SIZE + 10).unwrap();
+        // zeros that were written out in full are a hole too
+        file.write_all_at(&[0; 4_096], CHUNK_SIZE * 2).unwrap();
+
+        let chunks: Vec<_> = SparseChunks::new(file)
+            .unwrap()
+            .map(|chunk| match chunk.unwrap() {
+                SparseChunk::Data(chunk) => Err(chunk.data),
+                SparseChunk::Hole(len) => Ok(len),
+            })
+            .collect();
+
+        let mut data = vec![0; CHUNK_SIZE as usize];
+        data[10..14].copy_from_slice(b"data");
+        assert_eq!(chunks, [Ok(CHUNK_SIZE), Err(data), Ok(CHUNK_SIZE), Ok(100)]);
+
+        fs::remove_file(path).unwrap();
+    }
+}
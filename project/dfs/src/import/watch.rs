@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, spawn},
+    time::Duration,
+};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::cas::{directory::DirectoryEntry, object::Object};
+
+use super::{exclude::ExcludeRules, importer::Importer};
+
+/// How long to wait for a burst of changes to end before re-importing.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Watches an imported path and re-imports it whenever it changes, calling `on_change` with the
+/// new entry each time the resulting object is different from the last one, starting with
+/// `imported`, the object it was imported as before watching.
+///
+/// Once the watches are in place, everything is imported again to catch changes made since
+/// `imported`, which the import cache keeps cheap. After that, only the directories containing
+/// changes are walked again. Failing to handle a change, such as
+/// when something is deleted while it's being imported, is logged and everything is imported
+/// again with the next change. This blocks forever unless reading events fails.
+pub fn watch<F>(
+    importer: &Importer,
+    path: &Path,
+    imported: Option<Object>,
+    mut on_change: F,
+) -> io::Result<()>
+where
+    F: FnMut(DirectoryEntry),
+{
+    let root = env::current_dir()?.join(path);
+    let mut watcher = Watcher {
+        inotify: Inotify::init()?,
+        watches: HashMap::new(),
+    };
+
+    let rules = ExcludeRules::from_patterns(&root, &importer.options().exclude)?;
+    watcher.add_watches(&root, &rules)?;
+
+    let mut current = imported;
+    let mut publish = |entry: Option<DirectoryEntry>| {
+        if let Some(entry) = entry {
+            if current != Some(entry.file) {
+                current = Some(entry.file);
+                on_change(entry);
+            }
+        }
+    };
+
+    let mut buffer = [0; 4096];
+    // whether a change might have been missed, so that everything has to be looked at
+    let mut missed = false;
+
+    // anything that changed before the watches were added went unseen
+    match importer.import(&root) {
+        Ok(entry) => publish(entry),
+        Err(err) => {
+            eprintln!("Got error {} importing {}", err, root.display());
+            missed = true;
+        }
+    }
+
+    loop {
+        let mut events = watcher.read(&mut buffer, true)?;
+        loop {
+            thread::sleep(SETTLE_TIME);
+            let more = watcher.read(&mut buffer, false)?;
+            if more.is_empty() {
+                break;
+            }
+            events.extend(more);
+        }
+
+        let mut changed = Vec::new();
+        let mut overflowed = false;
+        for (path, mask) in events {
+            overflowed |= mask.contains(EventMask::Q_OVERFLOW);
+
+            if mask.contains(EventMask::ISDIR) {
+                if mask.contains(EventMask::MOVED_FROM) {
+                    // the watches would follow the directory to wherever it was moved
+                    watcher.remove_watches(&path);
+                }
+                if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    // new directories aren't watched yet, and neither is anything inside them
+                    let added =
+                        rules_above(&root, &path, &importer.options().exclude).and_then(|rules| {
+                            match rules.is_excluded(&path, true) {
+                                true => Ok(()),
+                                false => watcher.add_watches(&path, &rules),
+                            }
+                        });
+                    match added {
+                        // it was deleted again before we got to it
+                        Err(err) if err.kind() == ErrorKind::NotFound => {}
+                        Err(err) => {
+                            eprintln!("Got error {} watching {}", err, path.display());
+                            missed = true;
+                        }
+                        Ok(()) => {}
+                    }
+                }
+            }
+
+            changed.push(path);
+        }
+
+        let entry = if overflowed || missed {
+            // we don't know what changed, so look at everything
+            importer.import(&root)
+        } else {
+            importer.reimport(&root, &changed)
+        };
+        match entry {
+            Ok(entry) => {
+                missed = false;
+                publish(entry);
+            }
+            Err(err) => {
+                eprintln!("Got error {} importing {}", err, root.display());
+                missed = true;
+            }
+        }
+    }
+}
+
+/// Spawns a thread that watches `path` (see [`watch`]).
+pub fn spawn_watcher<F>(
+    importer: Arc<Importer>,
+    path: PathBuf,
+    imported: Option<Object>,
+    on_change: F,
+) where
+    F: FnMut(DirectoryEntry) + Send + 'static,
+{
+    spawn(move || {
+        if let Err(err) = watch(&importer, &path, imported, on_change) {
+            eprintln!("Got error {} watching {}", err, path.display());
+        }
+    });
+}
+
+/// Returns the exclude rules that apply to the entries of the directory containing `path`.
+fn rules_above(root: &Path, path: &Path, patterns: &[String]) -> io::Result<ExcludeRules> {
+    let mut rules = ExcludeRules::from_patterns(root, patterns)?;
+
+    let mut dirs = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(root))
+        .collect::<Vec<_>>();
+    dirs.reverse();
+    for dir in dirs {
+        rules = rules.enter_directory(dir)?;
+    }
+
+    Ok(rules)
+}
+
+struct Watcher {
+    inotify: Inotify,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    /// Watches `path` and, if it is a directory, every directory under it that isn't excluded.
+    fn add_watches(&mut self, path: &Path, rules: &ExcludeRules) -> io::Result<()> {
+        let mask = WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::ATTRIB
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVE
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+
+        let descriptor = self.inotify.watches().add(path, mask)?;
+        self.watches.insert(descriptor, path.to_path_buf());
+
+        if !path.is_dir() {
+            return Ok(());
+        }
+
+        let rules = rules.enter_directory(path)?;
+        for child in fs::read_dir(path)? {
+            let child = child?.path();
+            if child.is_dir() && !rules.is_excluded(&child, true) {
+                self.add_watches(&child, &rules)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops watching `path` and everything under it.
+    fn remove_watches(&mut self, path: &Path) {
+        let mut watches = self.inotify.watches();
+        self.watches.retain(|descriptor, dir| {
+            if dir.starts_with(path) {
+                // this fails if the directory has already been deleted, which is fine
+                let _ = watches.remove(descriptor.clone());
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Reads pending events as (path, mask) pairs, optionally waiting for at least one.
+    fn read(&mut self, buffer: &mut [u8], block: bool) -> io::Result<Vec<(PathBuf, EventMask)>> {
+        let events = if block {
+            self.inotify.read_events_blocking(buffer)?
+        } else {
+            match self.inotify.read_events(buffer) {
+                Ok(events) => events,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            }
+        };
+
+        let mut changes = Vec::new();
+        let mut removed = Vec::new();
+        for event in events {
+            if event.mask.contains(EventMask::IGNORED) {
+                // the watched directory is gone
+                removed.push(event.wd);
+                continue;
+            }
+
+            let Some(dir) = self.watches.get(&event.wd) else {
+                // overflows don't belong to any watch, and other events may be for watches we've
+                // just removed
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    changes.push((PathBuf::new(), event.mask));
+                }
+                continue;
+            };
+            let path = match event.name {
+                Some(name) => dir.join(name),
+                None => dir.clone(),
+            };
+            changes.push((path, event.mask));
+        }
+
+        for descriptor in removed {
+            self.watches.remove(&descriptor);
+        }
+
+        Ok(changes)
+    }
+}
@@ -2,6 +2,9 @@
 
 pub mod cas;
 pub mod dfs;
+pub mod export;
 pub mod fuse;
+pub mod http;
+pub mod import;
 pub mod network;
 pub mod store;
@@ -1,41 +1,213 @@
 use std::{
-    collections::BTreeMap,
+    collections::HashMap,
+    env,
     error::Error,
-    fs,
-    path::{Path, PathBuf},
+    path::PathBuf,
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use clap::Parser;
-use dfs::{cas::directory::DirectoryEntry, dfs::fs::Filesystem, fuse::MountedFilesystem};
-use fuser::MountOption;
-
-// todo: add an exclude flag or something
-
+use clap::{Parser, Subcommand};
+use dfs::{
+    cas::{
+        commit::{history, Commit},
+        diff::diff,
+        file::DEFAULT_INLINE_SIZE,
+        merge::merge,
+        object::Object,
+        ContentAddressedStore,
+    },
+    dfs::fs::Filesystem,
+    export::checkout,
+    fuse::{
+        MountOptions, MountedFilesystem, Prefetcher, DEFAULT_FUSE_THREADS, DEFAULT_MUTABLE_TTL,
+        DEFAULT_READAHEAD, DEFAULT_TTL,
+    },
+    http::spawn_gateway,
+    import::{spawn_watcher, ImportCache, ImportOptions, Importer},
+};
 #[derive(Parser)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Address to bind to
     bind: String,
     /// Mount point
-    mount: PathBuf,
+    mount: Option<PathBuf>,
 
     /// Paths to add to FS
     #[arg(short, long, use_value_delimiter = true, value_delimiter = ',')]
     files: Vec<PathBuf>,
+    /// Gitignore-style patterns to leave out when adding paths
+    #[arg(short, long, use_value_delimiter = true, value_delimiter = ',')]
+    exclude: Vec<String>,
+    /// Number of threads to add paths with (0 uses one per CPU)
+    #[arg(short, long, default_value_t = 0)]
+    jobs: usize,
+    /// Files up to this many bytes are stored inline instead of in a separate chunk
+    #[arg(long, default_value_t = DEFAULT_INLINE_SIZE)]
+    inline_size: u64,
+    /// Directory to keep the store in, so that it outlives the process
+    #[arg(long)]
+    store: Option<PathBuf>,
+    /// File to remember added files in, so that unchanged files aren't read again (only lasts
+    /// across runs along with --store)
+    #[arg(long)]
+    import_cache: Option<PathBuf>,
+    /// Keep added paths up to date as they change
+    #[arg(short, long)]
+    watch: bool,
     /// Peer address
     #[arg(short, long, use_value_delimiter = true, value_delimiter = ',')]
     peers: Option<Vec<String>>,
+    /// Addresses of nodes to join the network through, tried after remembered peers
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    bootstrap: Vec<String>,
+    /// File to remember peers in, so that the node rejoins the network after restarting
+    #[arg(long)]
+    peer_table: Option<PathBuf>,
+    /// Address to serve the read-only HTTP gateway on
+    #[arg(long)]
+    http: Option<String>,
+    /// Number of requests to the mount to handle at once (0 uses one per CPU)
+    #[arg(long, default_value_t = DEFAULT_FUSE_THREADS)]
+    fuse_threads: usize,
+    /// Let every user access the mount, not only the one mounting it and root
+    #[arg(long)]
+    allow_other: bool,
+    /// Have the kernel check access to the mount against file owners and modes
+    #[arg(long)]
+    default_permissions: bool,
+    /// Owner of files in the mount (defaults to the current user)
+    #[arg(long)]
+    uid: Option<u32>,
+    /// Group of files in the mount (defaults to the current group)
+    #[arg(long)]
+    gid: Option<u32>,
+    /// Seconds the kernel may cache objects in the mount for, which never change
+    #[arg(long, default_value_t = DEFAULT_TTL.as_secs())]
+    ttl: u64,
+    /// Seconds the kernel may cache named roots in the mount for, which change with each commit
+    #[arg(long, default_value_t = DEFAULT_MUTABLE_TTL.as_secs())]
+    mutable_ttl: u64,
+    /// Named root, hash or path to mount at the mount point instead of listing everything
+    #[arg(long)]
+    root: Option<String>,
+    /// Only list named roots at the mount point, not the hash of every object
+    #[arg(long)]
+    hide_hashes: bool,
+    /// Number of chunks to fetch ahead of files read sequentially through the mount (0 disables)
+    #[arg(long, default_value_t = DEFAULT_READAHEAD)]
+    readahead: u64,
+    /// Author to record in commits (defaults to $USER)
+    #[arg(long)]
+    author: Option<String>,
+}
+
+// commands to run against the filesystem instead of mounting it
+#[derive(Subcommand)]
+enum Command {
+    /// Show what changed between two trees
+    Diff {
+        /// Old tree, as a hash, named root or path
+        old: String,
+        /// New tree, as a hash, named root or path
+        new: String,
+        /// Also show which bytes of modified files changed
+        #[arg(long)]
+        chunks: bool,
+    },
+    /// Merge the changes between a base tree and another tree into a third
+    Merge {
+        /// Common ancestor of both trees, as a hash, named root or path
+        base: String,
+        /// Tree to merge into, as a hash, named root or path
+        ours: String,
+        /// Tree to merge from, as a hash, named root or path
+        theirs: String,
+        /// Named root to commit the merged tree to
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Show the history of a named root or commit
+    Log {
+        /// Named root or commit hash
+        reference: String,
+    },
+    /// Write a tree out to a new local directory
+    Checkout {
+        /// Named root, commit, hash or path to check out
+        reference: String,
+        /// Path to create
+        dest: PathBuf,
+    },
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
     let mut fs = Filesystem::new(args.bind);
+    fs.set_inline_size(args.inline_size);
+    if let Some(dir) = &args.store {
+        fs.open_store(dir)?;
+    }
+    let author = args
+        .author
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or("unknown".to_string());
 
+    let options = ImportOptions {
+        exclude: args.exclude,
+        jobs: args.jobs,
+    };
+    let cache = match &args.import_cache {
+        Some(path) => ImportCache::load(path)?,
+        None => ImportCache::new(),
+    };
+    let importer = Arc::new(Importer::new(fs.clone(), options, cache)?);
+    // each path becomes the named root of its base name, so two can't share one
+    let mut imported = HashMap::new();
     for file in &args.files {
-        let entry = add_entry(&mut fs, Path::new(file))?;
-        println!("adding {} ({})", file.to_str().unwrap(), entry.file)
+        if let Some(entry) = importer.import(file)? {
+            if let Some(other) = imported.insert(entry.name.clone(), file) {
+                return Err(format!(
+                    "{} and {} would both be imported as {}",
+                    other.display(),
+                    file.display(),
+                    entry.name
+                )
+                .into());
+            }
+            println!("adding {} ({})", file.to_str().unwrap(), entry.file);
+            let message = format!("import {}", file.display());
+            fs.commit(
+                &entry.name,
+                Commit::new(entry.file, vec![], &author, &message),
+            );
+
+            if args.watch {
+                let fs = fs.clone();
+                let author = author.clone();
+                let message = format!("update {}", file.display());
+                let imported = Some(entry.file);
+                spawn_watcher(importer.clone(), file.clone(), imported, move |entry| {
+                    println!("updating {} ({})", entry.name, entry.file);
+                    fs.commit(
+                        &entry.name,
+                        Commit::new(entry.file, vec![], &author, &message),
+                    );
+                });
+            }
+        }
+    }
+    if let Some(path) = &args.import_cache {
+        importer.cache().save(path)?;
+    }
+
+    if let Some(path) = args.peer_table {
+        fs.load_peer_table(path)?;
     }
 
     fs.run()?;
@@ -47,40 +219,144 @@ fn main() -> Result<(), Box<dyn Error>> {
             fs.add_peer(peer)?;
         }
     }
+    fs.join(&args.bootstrap)?;
+
+    if let Some(command) = args.command {
+        run_command(&fs, command, &author)?;
+
+        // keep serving the results of commands if we were asked to
+        if args.mount.is_none() && args.http.is_none() {
+            return Ok(());
+        }
+    }
+
+    if let Some(addr) = args.http {
+        spawn_gateway(fs.clone(), addr)?;
+    }
+
+    match args.mount {
+        Some(mount_point) => {
+            let defaults = MountOptions::default();
+            let options = MountOptions {
+                allow_other: args.allow_other,
+                default_permissions: args.default_permissions,
+                uid: args.uid.unwrap_or(defaults.uid),
+                gid: args.gid.unwrap_or(defaults.gid),
+                ttl: Duration::from_secs(args.ttl),
+                mutable_ttl: Duration::from_secs(args.mutable_ttl),
+                threads: args.fuse_threads,
+                root: args.root,
+                hide_hashes: args.hide_hashes,
+                ..defaults
+            };
+            let fuse_options = options.fuse_options();
+
+            let mut mount = MountedFilesystem::new(fs.clone(), options)?;
+            if args.readahead > 0 {
+                mount = mount.with_prefetcher(Prefetcher::spawn(fs.clone(), args.readahead));
+            }
+            fuser::mount2(mount, mount_point, &fuse_options)?;
+        }
+        // without a mount point we only serve peers and the gateway
+        None => loop {
+            thread::park();
+        },
+    }
+
+    Ok(())
+}
+
+fn run_command(fs: &Filesystem, command: Command, author: &str) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Diff { old, new, chunks } => {
+            let (old, new) = (resolve_tree(fs, &old)?, resolve_tree(fs, &new)?);
+            let changes = diff(fs, &old, &new, chunks)?;
+            for change in changes {
+                println!("{change}");
+            }
+        }
+        Command::Merge {
+            base,
+            ours,
+            theirs,
+            name,
+        } => {
+            let (base, our_tree, their_tree) = (
+                resolve_tree(fs, &base)?,
+                resolve_tree(fs, &ours)?,
+                resolve_tree(fs, &theirs)?,
+            );
+            let result = merge(fs, &base, &our_tree, &their_tree)?;
+            fs.add_resources(result.resources);
+
+            for conflict in &result.conflicts {
+                println!("conflict ({conflict})");
+            }
+            println!("merged ({})", result.root);
 
-    let mount = MountedFilesystem::new(&fs);
-    fuser::mount2(
-        mount,
-        args.mount,
-        &[MountOption::AllowRoot, MountOption::AutoUnmount],
-    )?;
+            if let Some(name) = name {
+                // both sides are parents, whatever the root pointed to before, as long as they
+                // have a history
+                let mut parents = Vec::new();
+                for side in [&ours, &theirs] {
+                    let object = resolve(fs, side)?;
+                    if fs.get::<Arc<Commit>>(&object).is_some() && !parents.contains(&object) {
+                        parents.push(object);
+                    }
+                }
+                let message = format!("merge {theirs}");
+                let commit = Commit::new(result.root, parents, author, &message);
+                fs.commit_with_parents(&name, commit);
+            }
+        }
+        Command::Log { reference } => log(fs, resolve(fs, &reference)?)?,
+        Command::Checkout { reference, dest } => {
+            checkout(fs, &resolve(fs, &reference)?, &dest)?;
+            println!("checked out {reference} to {}", dest.display());
+        }
+    }
 
     Ok(())
 }
 
-// todo: move this elsewhere
-fn add_entry(filesystem: &mut Filesystem, path: &Path) -> Result<DirectoryEntry, Box<dyn Error>> {
-    let item = if path.is_dir() {
-        let child_objs = BTreeMap::from_iter(
-            fs::read_dir(path)?
-                .map(|ch| {
-                    let entry = add_entry(filesystem, &ch?.path())?;
-                    Ok((entry.name, entry.file))
-                })
-                .collect::<Result<Vec<_>, Box<dyn Error>>>()?,
-        );
-
-        filesystem.create_directory(child_objs)
+/// Prints every commit reachable from `head`, newest first.
+fn log(fs: &Filesystem, head: Object) -> Result<(), Box<dyn Error>> {
+    if fs.get::<Arc<Commit>>(&head).is_none() {
+        return Err(format!("{head} is not a commit").into());
+    }
+
+    for (object, commit) in history(fs, head) {
+        println!("commit {object}");
+        if commit.parents.len() > 1 {
+            let parents = commit.parents.iter().map(Object::to_string);
+            println!("Merge: {}", parents.collect::<Vec<_>>().join(" "));
+        }
+        println!("Author: {}", commit.author);
+        let date = UNIX_EPOCH + Duration::from_secs(commit.timestamp);
+        println!("Date:   {}", httpdate::fmt_http_date(date));
+        println!("\n    {}\n", commit.message);
+    }
+
+    Ok(())
+}
+
+/// Resolves a hash, named root or path (with or without a leading '/') to an object.
+fn resolve(fs: &Filesystem, path: &str) -> Result<Object, Box<dyn Error>> {
+    let path = if path.starts_with('/') {
+        path.to_string()
     } else {
-        // todo: don't assume it's a file
-        let contents = fs::read(path)?;
-        filesystem.create_file(&contents[..])
+        format!("/{path}")
     };
 
-    let entry = DirectoryEntry {
-        name: path.file_name().unwrap().to_str().unwrap().to_string(), // todo: ew
-        file: item,
-    };
+    // named roots on their own resolve to their commit rather than its tree
+    if let Some(object) = fs.get_root(&path[1..]) {
+        return Ok(object);
+    }
+
+    Ok(fs.resolve_path(&path)?)
+}
 
-    Ok(entry)
+/// Resolves like [`resolve`], but to the tree of commits.
+fn resolve_tree(fs: &Filesystem, path: &str) -> Result<Object, Box<dyn Error>> {
+    Ok(fs.peel(resolve(fs, path)?))
 }
@@ -1,19 +1,42 @@
-use std::io::Error;
+use std::io::{self, Error, ErrorKind};
 use std::io::{Read, Write};
 
 use serde::{Deserialize, Serialize};
 use std::net::TcpStream;
 
+/// Largest packet that is read, bigger ones are skipped over.
+pub const MAX_PACKET_SIZE: u32 = 64 * 1024 * 1024;
+
 // todo: fix type safety stuff
 pub fn recv_packet<T: Serialize + for<'a> Deserialize<'a>>(
     stream: &mut TcpStream,
 ) -> Result<T, Error> {
+    let packet_buf = recv_raw_packet(stream)?.ok_or(Error::new(
+        ErrorKind::InvalidData,
+        format!("packet is bigger than {MAX_PACKET_SIZE} bytes"),
+    ))?;
+    Ok(serde_json::from_slice(&packet_buf)?)
+}
+
+/// Reads the next packet without parsing it.
+///
+/// Returns `None` for packets bigger than [`MAX_PACKET_SIZE`], after skipping over them so that
+/// the packet after can still be read.
+pub fn recv_raw_packet(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Error> {
     let mut len_buf = [0u8; 4];
     stream.read_exact(&mut len_buf)?;
     let length = u32::from_ne_bytes(len_buf);
+    if length > MAX_PACKET_SIZE {
+        let skipped = io::copy(&mut stream.take(length as u64), &mut io::sink())?;
+        if skipped < length as u64 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        return Ok(None);
+    }
+
     let mut packet_buf = vec![0; length as usize];
     stream.read_exact(&mut packet_buf)?;
-    Ok(serde_json::from_slice(&packet_buf)?)
+    Ok(Some(packet_buf))
 }
 
 pub fn send_packet<T: Serialize + for<'a> Deserialize<'a>>(
@@ -26,3 +49,46 @@ pub fn send_packet<T: Serialize + for<'a> Deserialize<'a>>(
     stream.write_all(&buf)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Returns both ends of a local connection.
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn skips_oversized_packets() {
+        let (mut client, mut server) = connection();
+        let writer = thread::spawn(move || {
+            let length = MAX_PACKET_SIZE + 1;
+            client.write_all(&length.to_ne_bytes()).unwrap();
+            io::copy(&mut io::repeat(b'x').take(length as u64), &mut client).unwrap();
+            send_packet(&mut client, &"next".to_string()).unwrap();
+        });
+
+        assert_eq!(recv_raw_packet(&mut server).unwrap(), None);
+        assert_eq!(recv_packet::<String>(&mut server).unwrap(), "next");
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn fails_on_truncated_oversized_packets() {
+        let (mut client, mut server) = connection();
+        client
+            .write_all(&(MAX_PACKET_SIZE + 1).to_ne_bytes())
+            .unwrap();
+        client.write_all(b"short").unwrap();
+        drop(client);
+
+        let err = recv_raw_packet(&mut server).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,8 +1,12 @@
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{self, Error, ErrorKind},
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::{Arc, Mutex, TryLockError},
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
 };
 
 use libc::EINVAL;
@@ -12,13 +16,29 @@ use crate::cas::{
 };
 
 use super::{
-    connection::{recv_packet, send_packet},
-    protocol::{AvailabilityCheckRequest, ConnectRequest, Request, ResourceRequest, Response},
+    peer::{Peer, KEEPALIVE_INTERVAL},
+    peer_table::PeerTable,
+    protocol::{AvailabilityCheckRequest, Request, ResourceRequest, ResourceResponse, Response},
 };
 
+/// Number of peers to stay connected to, beyond which no more are looked for.
+const TARGET_PEERS: usize = 8;
+
+/// Most peers to try connecting to at once, since each attempt can take a while.
+const MAX_CONNECT_ATTEMPTS: usize = 16;
+
+/// How often to ask peers for the peers they know about.
+const PEER_EXCHANGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 pub struct NetworkClient {
     host_address: SocketAddr,
-    peers: RefCell<HashMap<SocketAddr, TcpStream>>,
+    /// Connected peers, each behind its own lock so that requests to them can be made once the
+    /// client's lock is released (see [`NetworkClient::peers`])
+    peers: RefCell<HashMap<SocketAddr, Arc<Mutex<Peer>>>>,
+    /// Every peer we know about, connected or not (shared with the server, which hands it out)
+    table: Arc<Mutex<PeerTable>>,
+    /// File the peer table is saved to
+    table_path: Option<PathBuf>,
 }
 
 impl NetworkClient {
@@ -26,87 +46,305 @@ impl NetworkClient {
         Self {
             host_address: local_address,
             peers: RefCell::new(HashMap::new()),
+            table: Arc::new(Mutex::new(PeerTable::new())),
+            table_path: None,
         }
     }
 
-    pub fn add_peer<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
-        let addr = addr
-            .to_socket_addrs()
-            .map_err(|_| Error::from_raw_os_error(EINVAL))?
-            .next()
-            .ok_or(Error::from_raw_os_error(EINVAL))?;
-        if self.peers.borrow().contains_key(&addr) {
-            return Ok(());
+    /// Loads the peer table saved at `path`, if there is one, and saves it there from now on.
+    pub fn load_peer_table(&mut self, path: PathBuf) -> io::Result<()> {
+        *self.table.lock().unwrap() = PeerTable::load(&path)?;
+        self.table_path = Some(path);
+
+        Ok(())
+    }
+
+    pub fn save_peer_table(&self) -> io::Result<()> {
+        match &self.table_path {
+            Some(path) => self.table.lock().unwrap().save(path),
+            None => Ok(()),
         }
-        let peer = TcpStream::connect(addr)?;
-        self.peers.get_mut().insert(addr, peer);
-        let peer = self.peers.get_mut().get_mut(&addr).unwrap();
+    }
 
-        let req = Request::Connect(ConnectRequest {
-            addr: self.host_address,
-        });
-        send_packet(peer, &req)?;
+    pub fn peer_table(&self) -> Arc<Mutex<PeerTable>> {
+        self.table.clone()
+    }
 
-        Ok(())
+    /// Returns whether `addr` is worth connecting to, which it isn't if it's us or already
+    /// connected.
+    fn should_connect(&self, addr: &SocketAddr) -> bool {
+        *addr != self.host_address && !self.peers.borrow().contains_key(addr)
+    }
+
+    /// Adds a peer that was connected to without holding the client's lock, keeping the
+    /// existing connection if another one was made in the meantime.
+    fn insert_peer(&mut self, addr: SocketAddr, peer: Peer) {
+        self.peers
+            .get_mut()
+            .entry(addr)
+            .or_insert_with(|| Arc::new(Mutex::new(peer)));
     }
 
     pub fn request_resource(&mut self, obj: Object) -> Result<Resource, Error> {
+        self.get_resource_with_packed(&obj)
+            .map(|(resource, _)| resource)
+            .ok_or(Error::new(ErrorKind::NotFound, "Resource not found"))
+    }
+
+    /// Fetches a resource along with any small resources packed with it (see
+    /// [`ResourceResponse::packed`]).
+    pub fn get_resource_with_packed(&self, object: &Object) -> Option<(Resource, Vec<Resource>)> {
+        let response = Self::fetch(&self.peers(), object);
+        self.evict_failing_peers();
+        response
+    }
+
+    /// Returns the connected peers, which can still be used after the client's lock is released
+    /// so that waiting on one of them doesn't hold up everything else using the client.
+    pub fn peers(&self) -> Vec<(SocketAddr, Arc<Mutex<Peer>>)> {
+        self.peers
+            .borrow()
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.clone()))
+            .collect()
+    }
+
+    /// Fetches a resource along with any small resources packed with it from the first of
+    /// `peers` that has it (see [`NetworkClient::peers`]).
+    pub fn fetch(
+        peers: &[(SocketAddr, Arc<Mutex<Peer>>)],
+        object: &Object,
+    ) -> Option<(Resource, Vec<Resource>)> {
+        let response = peers.iter().find_map(|(addr, peer)| {
+            match Self::request_resource_from_peer(&mut peer.lock().unwrap(), object) {
+                Ok(response) => Some(response),
+                // peers not having something is expected, anything else is worth knowing about
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => {
+                    eprintln!("Got error {} while fetching {} from {}", err, object, addr);
+                    None
+                }
+            }
+        });
+
+        response.map(|resp| (resp.resource, resp.packed))
+    }
+
+    /// Drops peers that keep failing, leaving out ones that are busy with a request.
+    pub fn evict_failing_peers(&self) {
         self.peers
             .borrow_mut()
-            .iter_mut()
-            .find_map(|(_addr, peer)| Self::request_resource_from_peer(peer, &obj).ok())
-            .ok_or(Error::new(ErrorKind::NotFound, "Resource not found"))
+            .retain(|addr, peer| match peer.try_lock() {
+                Ok(peer) => {
+                    if peer.is_failing() {
+                        eprintln!("dropping peer {} after {} failures", addr, peer.failures());
+                    }
+                    !peer.is_failing()
+                }
+                Err(TryLockError::WouldBlock) => true,
+                Err(TryLockError::Poisoned(_)) => false,
+            });
     }
 
-    fn request_resource_from_peer(peer: &mut TcpStream, obj: &Object) -> Result<Resource, Error> {
+    fn request_resource_from_peer(
+        peer: &mut Peer,
+        obj: &Object,
+    ) -> Result<ResourceResponse, Error> {
         let req = Request::Resource(ResourceRequest { hash: *obj });
-        send_packet(peer, &req)?;
-        let response = recv_packet::<Response>(peer);
-        match response? {
-            Response::Resource(resp) => Ok(resp.resource),
+        match peer.request(&req)? {
+            Response::Resource(resp) => Ok(resp),
             Response::Redirect(_) => todo!(),
-            Response::AvailabilityCheck(_) => Err(Error::new(
-                ErrorKind::InvalidData,
-                "Unexpected value in response to ResourceRequest",
-            )),
-            Response::Error => Err(Error::new(ErrorKind::NotFound, "Resource not found")),
+            Response::AvailabilityCheck(_) | Response::Pong | Response::Peers(_) => {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected value in response to ResourceRequest",
+                ))
+            }
+            Response::Error(err) => Err(err.into()),
         }
     }
 
     fn request_availability_from_peer<T: Iterator<Item = Object>>(
-        peer: &mut TcpStream,
+        peer: &mut Peer,
         objs: T,
     ) -> Result<Vec<Object>, Error> {
         let req = Request::AvailabilityCheck(AvailabilityCheckRequest {
             hashes: objs.into_iter().collect(),
         });
-        send_packet(peer, &req)?;
 
-        match recv_packet::<Response>(peer)? {
+        match peer.request(&req)? {
             Response::AvailabilityCheck(resp) => Ok(resp.hashes),
-            Response::Redirect(_) | Response::Resource(_) => Err(Error::new(
-                ErrorKind::InvalidData,
-                "Unexpected response to AvailabilityCheckRequest",
-            )),
-            Response::Error => Err(Error::new(
-                ErrorKind::NotFound,
-                "Error in AvailabilityCheckRequest",
-            )),
+            Response::Redirect(_) | Response::Resource(_) | Response::Pong | Response::Peers(_) => {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected response to AvailabilityCheckRequest",
+                ))
+            }
+            Response::Error(err) => Err(err.into()),
         }
     }
 }
 
+/// Connects to the peer at `addr` without holding the client's lock while connecting.
+pub fn add_peer<A: ToSocketAddrs>(client: &Mutex<NetworkClient>, addr: A) -> io::Result<()> {
+    let addr = addr
+        .to_socket_addrs()
+        .map_err(|_| Error::from_raw_os_error(EINVAL))?
+        .next()
+        .ok_or(Error::from_raw_os_error(EINVAL))?;
+    let (host_address, table) = {
+        let client = client.lock().unwrap();
+        if client.peers.borrow().contains_key(&addr) {
+            return Ok(());
+        }
+        (client.host_address, client.table.clone())
+    };
+
+    let peer = Peer::connect(addr, host_address, table)?;
+    client.lock().unwrap().insert_peer(addr, peer);
+
+    Ok(())
+}
+
+/// Joins the network by connecting to the most reliable known peers, then to the bootstrap nodes
+/// if that isn't enough, and then asking them for more peers.
+pub fn join(client: &Mutex<NetworkClient>, bootstrap: &[SocketAddr]) {
+    let mut candidates = client.lock().unwrap().table.lock().unwrap().candidates();
+    candidates.extend(bootstrap);
+
+    connect_to(client, candidates);
+    exchange_peers(client);
+}
+
+/// Asks connected peers for the peers they know about, remembering them and connecting to them
+/// while we have fewer than [`TARGET_PEERS`].
+///
+/// The client is only locked to look up peers and to record what they said, not while waiting
+/// on them.
+pub fn exchange_peers(client: &Mutex<NetworkClient>) {
+    let (node_id, peers) = {
+        let client = client.lock().unwrap();
+        let node_id = client.table.lock().unwrap().node_id();
+        (node_id, client.peers())
+    };
+
+    let mut learned = Vec::new();
+    let mut ourselves = Vec::new();
+    for (addr, peer) in peers {
+        match peer.lock().unwrap().exchange() {
+            // we can be known by an address other than the one we bind to
+            Ok((peer_id, _)) if peer_id == node_id => ourselves.push(addr),
+            Ok((_, peers)) => learned.extend(peers),
+            Err(err) => eprintln!("Got error {} while asking {} for peers", err, addr),
+        }
+    }
+
+    let candidates = {
+        let mut client = client.lock().unwrap();
+        let host_address = client.host_address;
+        let table = client.table.clone();
+        let mut table = table.lock().unwrap();
+        for addr in ourselves {
+            client.peers.get_mut().remove(&addr);
+            table.forget(&addr);
+        }
+        learned.retain(|addr| *addr != host_address && table.get(addr).is_none());
+        for addr in &learned {
+            table.learn(*addr);
+        }
+        // peers we already knew about but aren't connected to get another chance too
+        table.candidates()
+    };
+
+    learned.extend(candidates);
+    connect_to(client, learned);
+    client.lock().unwrap().evict_failing_peers();
+}
+
+/// Connects to peers from `candidates`, in order and skipping repeats, until there are
+/// [`TARGET_PEERS`].
+fn connect_to(client: &Mutex<NetworkClient>, candidates: Vec<SocketAddr>) {
+    let mut seen = HashSet::new();
+    let attempts = {
+        let client = client.lock().unwrap();
+        candidates
+            .into_iter()
+            .filter(|addr| client.should_connect(addr))
+            .filter(|addr| seen.insert(*addr))
+            .take(MAX_CONNECT_ATTEMPTS)
+            .collect::<Vec<_>>()
+    };
+
+    for addr in attempts {
+        if client.lock().unwrap().peers.borrow().len() >= TARGET_PEERS {
+            break;
+        }
+        if let Err(err) = add_peer(client, addr) {
+            eprintln!("Got error {} while connecting to {}", err, addr);
+        }
+    }
+}
+
+/// Pings peers that haven't been heard from in a while, which also reconnects to dropped peers
+/// once their backoff has passed, and drops peers that keep failing.
+///
+/// Known peers that aren't connected, like ones that have connected to us since, are then
+/// connected to while we have fewer than [`TARGET_PEERS`].
+pub fn keepalive(client: &Mutex<NetworkClient>) {
+    let peers = client.lock().unwrap().peers();
+    for (addr, peer) in peers {
+        let mut peer = peer.lock().unwrap();
+        if peer.needs_ping() {
+            if let Err(err) = peer.ping() {
+                eprintln!("Got error {} while pinging {}", err, addr);
+            }
+        }
+    }
+    client.lock().unwrap().evict_failing_peers();
+
+    let candidates = {
+        let client = client.lock().unwrap();
+        if client.peers.borrow().len() < TARGET_PEERS {
+            client.table.lock().unwrap().candidates()
+        } else {
+            Vec::new()
+        }
+    };
+    connect_to(client, candidates);
+}
+
+/// Keeps the connections of `client` alive in the background (see [`keepalive`]), looks for
+/// more peers every so often and saves the peer table.
+pub fn spawn_keepalive(client: Arc<Mutex<NetworkClient>>) {
+    spawn(move || {
+        let mut last_exchange = Instant::now();
+        loop {
+            sleep(KEEPALIVE_INTERVAL);
+            keepalive(&client);
+            if last_exchange.elapsed() >= PEER_EXCHANGE_INTERVAL {
+                exchange_peers(&client);
+                last_exchange = Instant::now();
+            }
+            if let Err(err) = client.lock().unwrap().save_peer_table() {
+                eprintln!("Got error {} while saving peer table", err);
+            }
+        }
+    });
+}
+
 impl ContentAddressedStore for NetworkClient {
     fn get_resource(&self, object: &Object) -> Option<Resource> {
-        self.peers
-            .borrow_mut()
-            .iter_mut()
-            .find_map(|(_addr, peer)| Self::request_resource_from_peer(peer, object).ok())
+        self.get_resource_with_packed(object)
+            .map(|(resource, _)| resource)
     }
 
     fn has(&self, object: &Object) -> bool {
-        self.peers.borrow_mut().iter_mut().any(|(_addr, peer)| {
-            Self::request_availability_from_peer(peer, [object].iter().cloned().cloned()).is_ok()
+        self.peers().iter().any(|(_addr, peer)| {
+            Self::request_availability_from_peer(
+                &mut peer.lock().unwrap(),
+                [object].iter().cloned().cloned(),
+            )
+            .is_ok()
         })
     }
 
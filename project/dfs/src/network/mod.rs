@@ -1,4 +1,8 @@
 pub mod connection;
 pub mod fs;
+mod peer;
+pub mod peer_table;
 mod protocol;
 pub mod server;
+
+pub use protocol::ServerError;
@@ -0,0 +1,179 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    connection::{recv_packet, send_packet},
+    peer_table::{NodeId, PeerTable},
+    protocol::{ConnectRequest, Request, Response},
+};
+
+/// How long to wait for a connection to a peer to be accepted.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a peer can go without sending or accepting any data in the middle of a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer can go without being heard from before it's pinged.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before reconnecting after the first failure, which doubles with every failure
+/// after that.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest time to wait before reconnecting.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Number of failures in a row after which a peer is dropped.
+const MAX_FAILURES: u32 = 10;
+
+/// A connection to another node, which is dropped when a request to it fails and re-established
+/// with exponential backoff.
+///
+/// How every request turns out is recorded in the peer table.
+pub struct Peer {
+    addr: SocketAddr,
+    /// Our own address, to announce when connecting
+    host_address: SocketAddr,
+    stream: Option<TcpStream>,
+    /// Number of requests or connection attempts in a row that have failed
+    failures: u32,
+    /// When to next try connecting, if disconnected
+    retry_at: Instant,
+    /// When the peer last answered a request
+    last_seen: Instant,
+    table: Arc<Mutex<PeerTable>>,
+}
+
+impl Peer {
+    /// Connects to the peer at `addr` and announces ourselves to it.
+    pub fn connect(
+        addr: SocketAddr,
+        host_address: SocketAddr,
+        table: Arc<Mutex<PeerTable>>,
+    ) -> io::Result<Self> {
+        let stream = match Self::open(addr, host_address) {
+            Ok(stream) => stream,
+            Err(err) => {
+                table.lock().unwrap().failed(addr);
+                return Err(err);
+            }
+        };
+        table.lock().unwrap().succeeded(addr);
+
+        Ok(Peer {
+            addr,
+            host_address,
+            stream: Some(stream),
+            failures: 0,
+            retry_at: Instant::now(),
+            last_seen: Instant::now(),
+            table,
+        })
+    }
+
+    fn open(addr: SocketAddr, host_address: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let req = Request::Connect(ConnectRequest { addr: host_address });
+        send_packet(&mut stream, &req)?;
+
+        Ok(stream)
+    }
+
+    /// Sends a request and waits for the response, reconnecting first if the peer was dropped and
+    /// its backoff has passed.
+    ///
+    /// Any failure drops the connection, since the stream could be left in the middle of a packet.
+    pub fn request(&mut self, req: &Request) -> io::Result<Response> {
+        if self.stream.is_none() {
+            if Instant::now() < self.retry_at {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    format!("waiting to reconnect to {}", self.addr),
+                ));
+            }
+
+            match Self::open(self.addr, self.host_address) {
+                Ok(stream) => self.stream = Some(stream),
+                Err(err) => {
+                    self.fail();
+                    return Err(err);
+                }
+            }
+        }
+
+        let stream = self.stream.as_mut().expect("peer should be connected");
+        let response = send_packet(stream, req).and_then(|()| recv_packet::<Response>(stream));
+        match response {
+            Ok(response) => {
+                self.failures = 0;
+                self.last_seen = Instant::now();
+                self.table.lock().unwrap().succeeded(self.addr);
+                Ok(response)
+            }
+            Err(err) => {
+                self.fail();
+                Err(err)
+            }
+        }
+    }
+
+    /// Checks that the peer is still there.
+    pub fn ping(&mut self) -> io::Result<()> {
+        match self.request(&Request::Ping)? {
+            Response::Pong => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unexpected response to Ping",
+            )),
+        }
+    }
+
+    /// Asks the peer for its ID and the peers it knows about.
+    pub fn exchange(&mut self) -> io::Result<(NodeId, Vec<SocketAddr>)> {
+        match self.request(&Request::Peers)? {
+            Response::Peers(resp) => {
+                self.table
+                    .lock()
+                    .unwrap()
+                    .set_node_id(self.addr, resp.node_id);
+                Ok((resp.node_id, resp.peers))
+            }
+            Response::Error(err) => Err(err.into()),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unexpected response to Peers",
+            )),
+        }
+    }
+
+    /// Returns whether the peer hasn't been heard from in a while and can be tried now.
+    pub fn needs_ping(&self) -> bool {
+        self.last_seen.elapsed() >= KEEPALIVE_INTERVAL
+            && (self.stream.is_some() || Instant::now() >= self.retry_at)
+    }
+
+    /// Returns whether the peer has failed too many times in a row to keep trying.
+    pub fn is_failing(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    fn fail(&mut self) {
+        self.stream = None;
+        self.failures += 1;
+        self.table.lock().unwrap().failed(self.addr);
+
+        let backoff = BASE_BACKOFF.saturating_mul(1 << (self.failures - 1).min(16));
+        self.retry_at = Instant::now() + backoff.min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+
+/// Most peers to remember, the least reliable ones are forgotten first.
+const MAX_KNOWN_PEERS: usize = 1024;
+
+/// Most peers to tell another node about when it asks for them.
+const MAX_SHARED_PEERS: usize = 32;
+
+/// Number of failures after which a peer that has never answered is forgotten.
+const MAX_FAILURES_UNSEEN: u64 = 5;
+
+/// Identifies a node across restarts and address changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId([u8; 16]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0; 16];
+        // getrandom can be interrupted by a signal or return fewer bytes than asked for
+        let mut filled = 0;
+        while filled < id.len() {
+            let rest = &mut id[filled..];
+            let read = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+            if read < 0 {
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    panic!("Got error {} while generating a node ID", err);
+                }
+                continue;
+            }
+            filled += read as usize;
+        }
+
+        NodeId(id)
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.encode_hex::<String>())
+    }
+}
+
+/// What is known about a peer.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct PeerRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<NodeId>,
+    /// Seconds since the Unix epoch when the peer last answered a request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+    /// Number of requests and connection attempts that went through
+    pub successes: u64,
+    /// Number of requests and connection attempts that failed
+    pub failures: u64,
+}
+
+impl PeerRecord {
+    /// Returns the share of requests to the peer that went through, or 0 if none were made.
+    pub fn success_rate(&self) -> f64 {
+        match self.successes + self.failures {
+            0 => 0.0,
+            total => self.successes as f64 / total as f64,
+        }
+    }
+}
+
+/// The peers a node knows about, along with its own ID, which can be saved so that the node
+/// rejoins the network after restarting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerTable {
+    node_id: NodeId,
+    peers: HashMap<SocketAddr, PeerRecord>,
+}
+
+impl PeerTable {
+    pub fn new() -> Self {
+        Self {
+            node_id: NodeId::random(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Loads a table saved with [`PeerTable::save`], or a new one if `path` doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = serde_json::to_vec(self).map_err(Error::other)?;
+        fs::write(path, contents)
+    }
+
+    /// Returns the ID of this node.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerRecord> {
+        self.peers.get(addr)
+    }
+
+    /// Remembers a peer, if it isn't known already.
+    ///
+    /// Once [`MAX_KNOWN_PEERS`] are known, the least reliable peer that has failed makes room for
+    /// it, and if none have failed, the new peer isn't remembered.
+    pub fn learn(&mut self, addr: SocketAddr) {
+        if self.peers.contains_key(&addr) {
+            return;
+        }
+
+        if self.peers.len() >= MAX_KNOWN_PEERS {
+            let worst = self
+                .candidates()
+                .into_iter()
+                .rev()
+                .find(|addr| self.peers[addr].failures > 0);
+            match worst {
+                Some(worst) => self.peers.remove(&worst),
+                None => return,
+            };
+        }
+        self.peers.insert(addr, PeerRecord::default());
+    }
+
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// Records a request to or connection with the peer that went through.
+    pub fn succeeded(&mut self, addr: SocketAddr) {
+        self.learn(addr);
+        if let Some(record) = self.peers.get_mut(&addr) {
+            record.successes += 1;
+            record.last_seen = Some(now());
+        }
+    }
+
+    /// Records a request to or connection with the peer that failed, forgetting it if it has
+    /// never answered and keeps failing.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        self.learn(addr);
+        if let Some(record) = self.peers.get_mut(&addr) {
+            record.failures += 1;
+            if record.last_seen.is_none() && record.failures >= MAX_FAILURES_UNSEEN {
+                self.peers.remove(&addr);
+            }
+        }
+    }
+
+    pub fn set_node_id(&mut self, addr: SocketAddr, node_id: NodeId) {
+        self.learn(addr);
+        if let Some(record) = self.peers.get_mut(&addr) {
+            record.node_id = Some(node_id);
+        }
+    }
+
+    /// Returns every known peer, most reliable first, so that the best ones are connected to.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by(|(_, a), (_, b)| {
+            b.success_rate()
+                .total_cmp(&a.success_rate())
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+
+        peers.into_iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Returns the peers worth telling another node about: the ones that have answered, most
+    /// recently seen first.
+    pub fn shared(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(addr, record)| Some((record.last_seen?, *addr)))
+            .collect();
+        peers.sort_by_key(|(last_seen, _)| Reverse(*last_seen));
+
+        peers
+            .into_iter()
+            .take(MAX_SHARED_PEERS)
+            .map(|(_, addr)| addr)
+            .collect()
+    }
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8)),
+            4000,
+        )
+    }
+
+    #[test]
+    fn orders_candidates_by_success_rate_then_last_seen() {
+        let mut table = PeerTable::new();
+        table.learn(addr(0));
+        table.failed(addr(1));
+        table.succeeded(addr(2));
+        table.succeeded(addr(3));
+        table.failed(addr(3));
+        table.succeeded(addr(4));
+        table.peers.get_mut(&addr(4)).unwrap().last_seen = Some(now() - 60);
+
+        let candidates = table.candidates();
+        assert_eq!(candidates[..3], [addr(2), addr(4), addr(3)]);
+        // the two that have never answered come last, in no particular order
+        assert_eq!(candidates.len(), 5);
+    }
+
+    #[test]
+    fn shares_peers_that_have_answered_most_recent_first() {
+        let mut table = PeerTable::new();
+        table.failed(addr(0));
+        for i in 1..=MAX_SHARED_PEERS + 1 {
+            table.succeeded(addr(i));
+            table.peers.get_mut(&addr(i)).unwrap().last_seen = Some(i as u64);
+        }
+
+        let shared = table.shared();
+        assert_eq!(shared.len(), MAX_SHARED_PEERS);
+        assert_eq!(shared[0], addr(MAX_SHARED_PEERS + 1));
+        assert!(!shared.contains(&addr(0)));
+        assert!(!shared.contains(&addr(1)));
+    }
+
+    #[test]
+    fn forgets_unseen_peers_that_keep_failing() {
+        let mut table = PeerTable::new();
+        table.succeeded(addr(1));
+        for _ in 0..MAX_FAILURES_UNSEEN {
+            table.failed(addr(0));
+            table.failed(addr(1));
+        }
+
+        assert!(table.get(&addr(0)).is_none());
+        assert!(table.get(&addr(1)).is_some());
+    }
+
+    #[test]
+    fn evicts_the_worst_failing_peer_when_full() {
+        let mut table = PeerTable::new();
+        for i in 0..MAX_KNOWN_PEERS {
+            table.succeeded(addr(i));
+        }
+        table.failed(addr(0));
+        table.failed(addr(1));
+        table.failed(addr(1));
+
+        table.learn(addr(MAX_KNOWN_PEERS));
+        assert!(table.get(&addr(MAX_KNOWN_PEERS)).is_some());
+        assert!(table.get(&addr(1)).is_none());
+        assert!(table.get(&addr(0)).is_some());
+        assert_eq!(table.peers.len(), MAX_KNOWN_PEERS);
+    }
+
+    #[test]
+    fn turns_new_peers_away_when_full_of_working_ones() {
+        let mut table = PeerTable::new();
+        for i in 0..MAX_KNOWN_PEERS {
+            table.succeeded(addr(i));
+        }
+
+        table.learn(addr(MAX_KNOWN_PEERS));
+        table.failed(addr(MAX_KNOWN_PEERS + 1));
+        assert!(table.get(&addr(MAX_KNOWN_PEERS)).is_none());
+        assert!(table.get(&addr(MAX_KNOWN_PEERS + 1)).is_none());
+        assert_eq!(table.peers.len(), MAX_KNOWN_PEERS);
+    }
+
+    #[test]
+    fn makes_distinct_node_ids() {
+        assert_ne!(NodeId::random(), NodeId::random());
+    }
+}
@@ -1,9 +1,11 @@
-use std::net::SocketAddr;
+use std::{fmt::Display, io, net::SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
 use crate::cas::{object::Object, resource::Resource};
 
+use super::peer_table::NodeId;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectRequest {
     pub addr: SocketAddr,
@@ -17,6 +19,9 @@ pub struct ResourceRequest {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResourceResponse {
     pub resource: Resource,
+    /// Small resources sent along with a directory (its inline files), saving a round trip each
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packed: Vec<Resource>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,11 +40,22 @@ pub struct AvailabilityCheckResponse {
     pub hashes: Vec<Object>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersResponse {
+    /// ID of the answering node, so that nodes can recognise each other at different addresses
+    pub node_id: NodeId,
+    pub peers: Vec<SocketAddr>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Connect(ConnectRequest),
     Resource(ResourceRequest),
     AvailabilityCheck(AvailabilityCheckRequest),
+    /// Checks that the connection is still alive
+    Ping,
+    /// Asks for the peers the node knows about
+    Peers,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,5 +63,77 @@ pub enum Response {
     Resource(ResourceResponse),
     Redirect(RedirectResponse),
     AvailabilityCheck(AvailabilityCheckResponse),
-    Error,
+    Pong,
+    Peers(PeersResponse),
+    Error(ServerError),
+}
+
+/// Why a server couldn't answer a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerError {
+    /// The server doesn't have what was asked for
+    NotFound,
+    /// The request couldn't be parsed
+    BadRequest,
+    /// The request was bigger than the server accepts
+    TooLarge,
+    /// Too many requests were made over the connection, so it should be tried again later
+    RateLimited,
+    /// Something went wrong in the server itself
+    Internal,
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            ServerError::NotFound => "not found",
+            ServerError::BadRequest => "bad request",
+            ServerError::TooLarge => "request too large",
+            ServerError::RateLimited => "rate limited",
+            ServerError::Internal => "internal server error",
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Keeps the [`ServerError`] as the inner error, so that callers can tell the kinds apart with
+/// [`io::Error::get_ref`].
+impl From<ServerError> for io::Error {
+    fn from(value: ServerError) -> Self {
+        let kind = match value {
+            ServerError::NotFound => io::ErrorKind::NotFound,
+            ServerError::BadRequest => io::ErrorKind::InvalidInput,
+            ServerError::TooLarge => io::ErrorKind::InvalidData,
+            ServerError::RateLimited | ServerError::Internal => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_server_errors_to_io_errors() {
+        let cases = [
+            (ServerError::NotFound, io::ErrorKind::NotFound),
+            (ServerError::BadRequest, io::ErrorKind::InvalidInput),
+            (ServerError::TooLarge, io::ErrorKind::InvalidData),
+            (ServerError::RateLimited, io::ErrorKind::Other),
+            (ServerError::Internal, io::ErrorKind::Other),
+        ];
+
+        for (server_error, kind) in cases {
+            let err = io::Error::from(server_error);
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.to_string(), server_error.to_string());
+
+            // kinds that share an io::ErrorKind can still be told apart
+            let inner = err.get_ref().and_then(|inner| inner.downcast_ref());
+            assert_eq!(inner, Some(&server_error));
+        }
+    }
 }
@@ -1,20 +1,31 @@
 use std::{
-    io::Error,
+    io::{Error, ErrorKind},
     net::{TcpListener, TcpStream, ToSocketAddrs},
     sync::{Arc, Mutex},
     thread::spawn,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    cas::{resource::Resource, ContentAddressedStore},
+    cas::{directory::Directory, file::File, resource::Resource, ContentAddressedStore},
     network::{
         connection::send_packet,
-        protocol::{AvailabilityCheckResponse, ResourceResponse, Response},
+        peer_table::PeerTable,
+        protocol::{
+            AvailabilityCheckResponse, PeersResponse, ResourceResponse, Response, ServerError,
+        },
     },
     store::fs::LocalStore,
 };
 
-use super::{connection::recv_packet, fs::NetworkClient, protocol::Request};
+use super::{connection::recv_raw_packet, fs::NetworkClient, protocol::Request};
+
+/// Most bytes of inline files to send along with a directory.
+const PACKED_BYTES: u64 = 256 * 1024;
+
+/// Most requests a single connection can make per second before being told to back off with
+/// [`ServerError::RateLimited`].
+const MAX_REQUESTS_PER_SECOND: u32 = 1_000;
 
 pub fn run_server<A: ToSocketAddrs>(
     fs: Arc<Mutex<LocalStore>>,
@@ -23,15 +34,17 @@ pub fn run_server<A: ToSocketAddrs>(
 ) -> Result<(), Error> {
     let listener = TcpListener::bind(addr)?;
     let incoming = listener.incoming();
+    // peers are handed out without locking the client, which may be busy waiting on a peer
+    let table = client.lock().unwrap().peer_table();
 
     for stream in incoming {
         let stream = stream?;
         let fs = fs.clone();
-        let client = client.clone();
+        let table = table.clone();
 
         println!("new connection");
 
-        spawn(move || host_connection_loop(fs, client, stream));
+        spawn(move || host_connection_loop(fs, table, stream));
     }
 
     Ok(())
@@ -39,49 +52,152 @@ pub fn run_server<A: ToSocketAddrs>(
 
 fn host_connection_loop(
     fs: Arc<Mutex<LocalStore>>,
-    client: Arc<Mutex<NetworkClient>>,
+    table: Arc<Mutex<PeerTable>>,
     mut stream: TcpStream,
 ) {
+    let mut limiter = RateLimiter::new();
     loop {
-        let request = recv_packet::<Request>(&mut stream);
-        match request {
-            Ok(Request::Connect(req)) => {
-                // todo: remove unwrap
-                client.lock().unwrap().add_peer(req.addr).unwrap();
-            }
-            Ok(Request::Resource(res)) => {
-                let fs = fs.lock().unwrap();
-                let resource: Option<Resource> = fs.get::<Resource>(&res.hash);
-                let response = if let Some(resource) = resource {
-                    Response::Resource(ResourceResponse { resource })
-                } else {
-                    Response::Error
-                };
-                drop(fs);
-                send_packet(&mut stream, &response).unwrap();
+        // only a broken stream ends the connection, anything wrong with a single request is
+        // answered with an error
+        let packet = match recv_raw_packet(&mut stream) {
+            Ok(packet) => packet,
+            Err(err) => {
+                if err.kind() != ErrorKind::UnexpectedEof {
+                    eprintln!("Got error {} in server", err);
+                }
+                break;
             }
-            Ok(Request::AvailabilityCheck(ac)) => {
-                let fs = fs.lock().unwrap();
-                let found_hashes = ac
-                    .hashes
-                    .iter()
-                    .filter(|&hash| fs.has(hash))
-                    .cloned()
-                    .collect();
-                let response = Response::AvailabilityCheck(AvailabilityCheckResponse {
-                    hashes: found_hashes,
-                });
-                drop(fs);
-                send_packet(&mut stream, &response).unwrap();
+        };
+
+        let response = match packet.map(|packet| serde_json::from_slice::<Request>(&packet)) {
+            None => Some(Response::Error(ServerError::TooLarge)),
+            Some(Err(err)) => {
+                eprintln!("Got error {} while parsing request", err);
+                Some(Response::Error(ServerError::BadRequest))
             }
-            Err(err) => {
-                eprintln!("Got error {} in server", err);
+            Some(Ok(request)) => handle_request(&fs, &table, &mut limiter, request),
+        };
+
+        if let Some(response) = response {
+            if let Err(err) = send_packet(&mut stream, &response) {
+                eprintln!("Got error {} while sending response", err);
                 break;
             }
         }
     }
 }
 
+/// Returns the response to a request, if it gets one.
+fn handle_request(
+    fs: &Mutex<LocalStore>,
+    table: &Mutex<PeerTable>,
+    limiter: &mut RateLimiter,
+    request: Request,
+) -> Option<Response> {
+    match request {
+        Request::Connect(req) => {
+            // the peer is connected back to by keepalive, rather than here with the client locked
+            // while waiting on it
+            if let Ok(mut table) = table.lock() {
+                table.learn(req.addr);
+            }
+            None
+        }
+        _ if !limiter.allow() => Some(Response::Error(ServerError::RateLimited)),
+        Request::Resource(res) => {
+            let Ok(fs) = fs.lock() else {
+                return Some(Response::Error(ServerError::Internal));
+            };
+            let response = match fs.get::<Resource>(&res.hash) {
+                Some(resource) => {
+                    let packed = match &resource {
+                        Resource::Directory(dir) => pack_children(&fs, dir),
+                        _ => Vec::new(),
+                    };
+                    Response::Resource(ResourceResponse { resource, packed })
+                }
+                None => Response::Error(ServerError::NotFound),
+            };
+            Some(response)
+        }
+        Request::AvailabilityCheck(ac) => {
+            let Ok(fs) = fs.lock() else {
+                return Some(Response::Error(ServerError::Internal));
+            };
+            let found_hashes = ac
+                .hashes
+                .iter()
+                .filter(|&hash| fs.has(hash))
+                .cloned()
+                .collect();
+            Some(Response::AvailabilityCheck(AvailabilityCheckResponse {
+                hashes: found_hashes,
+            }))
+        }
+        Request::Ping => Some(Response::Pong),
+        Request::Peers => {
+            let Ok(table) = table.lock() else {
+                return Some(Response::Error(ServerError::Internal));
+            };
+            Some(Response::Peers(PeersResponse {
+                node_id: table.node_id(),
+                peers: table.shared(),
+            }))
+        }
+    }
+}
+
+/// Counts the requests made over a connection in one second windows.
+struct RateLimiter {
+    window_start: Instant,
+    requests: u32,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            requests: 0,
+        }
+    }
+
+    /// Records a request and returns whether it's within [`MAX_REQUESTS_PER_SECOND`].
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.requests = 0;
+        }
+        self.requests += 1;
+
+        self.requests <= MAX_REQUESTS_PER_SECOND
+    }
+}
+
+/// Returns the inline files in a directory, up to [`PACKED_BYTES`] of them, so that they can be
+/// sent along with it.
+fn pack_children(fs: &LocalStore, dir: &Directory) -> Vec<Resource> {
+    // the entries of sharded directories aren't all at hand
+    if dir.is_sharded() {
+        return Vec::new();
+    }
+
+    let mut packed = Vec::new();
+    let mut bytes = 0;
+    for entry in dir.get_children(fs).filter_map(Result::ok) {
+        let Some(file) = fs.get::<Arc<File>>(&entry.file) else {
+            continue;
+        };
+        if !file.is_inline() || bytes + file.size > PACKED_BYTES {
+            continue;
+        }
+
+        bytes += file.size;
+        packed.push(file.into());
+    }
+
+    packed
+}
+
 // todo: get rid of 'static somehow
 pub fn spawn_server<A: ToSocketAddrs + Send + 'static>(
     fs: Arc<Mutex<LocalStore>>,
@@ -90,3 +206,48 @@ pub fn spawn_server<A: ToSocketAddrs + Send + 'static>(
 ) {
     spawn(move || run_server(fs, client, addr));
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::network::protocol::ConnectRequest;
+
+    use super::*;
+
+    #[test]
+    fn limits_requests_per_second() {
+        let mut limiter = RateLimiter::new();
+        assert!((0..MAX_REQUESTS_PER_SECOND).all(|_| limiter.allow()));
+        assert!(!limiter.allow());
+
+        limiter.window_start -= Duration::from_secs(1);
+        assert!(limiter.allow());
+    }
+
+    #[test]
+    fn answers_rate_limited_requests_with_an_error() {
+        let fs = Mutex::new(LocalStore::new());
+        let table = Mutex::new(PeerTable::new());
+        let mut limiter = RateLimiter::new();
+        for _ in 0..MAX_REQUESTS_PER_SECOND {
+            limiter.allow();
+        }
+
+        assert!(matches!(
+            handle_request(&fs, &table, &mut limiter, Request::Ping),
+            Some(Response::Error(ServerError::RateLimited))
+        ));
+    }
+
+    #[test]
+    fn remembers_connecting_peers_without_answering() {
+        let fs = Mutex::new(LocalStore::new());
+        let table = Mutex::new(PeerTable::new());
+        let addr = "127.0.0.1:4000".parse().unwrap();
+
+        let request = Request::Connect(ConnectRequest { addr });
+        let response = handle_request(&fs, &table, &mut RateLimiter::new(), request);
+
+        assert!(response.is_none());
+        assert!(table.lock().unwrap().get(&addr).is_some());
+    }
+}
@@ -1,77 +1,417 @@
 use std::{
-    collections::BTreeMap,
+    cell::RefCell,
+    collections::{btree_map::Entry, BTreeMap, HashSet},
+    ffi::CString,
+    fs,
+    io::{self, Error, ErrorKind, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
+use hex::ToHex;
+
 use crate::cas::{
-    chunk::Chunk, directory::Directory, error::PathResolutionError, file::File, object::Object,
-    resource::Resource, ContentAddressedStore,
+    chunk::Chunk,
+    commit::Commit,
+    directory::Directory,
+    error::PathResolutionError,
+    file::{File, FileBuilder, DEFAULT_INLINE_SIZE},
+    object::Object,
+    resource::Resource,
+    ContentAddressedStore, StoreUsage,
 };
 
-#[derive(Default, Debug)]
+/// Directory that objects other than chunks are saved in as JSON, under the store's directory.
+const OBJECTS_DIR: &str = "objects";
+/// Directory that chunks are saved in as their raw bytes, under the store's directory.
+const CHUNKS_DIR: &str = "chunks";
+/// File that named roots are saved in, under the store's directory.
+const ROOTS_FILE: &str = "roots.json";
+
+/// Where a resource in a [`LocalStore`] is kept.
+#[derive(Debug)]
+enum Stored {
+    /// In memory
+    Loaded(Resource),
+    /// In the store's directory, and not read yet
+    Saved,
+    /// In the store's directory as a raw chunk, which is read whenever it's needed instead of
+    /// being kept in memory
+    Chunk,
+}
+
+/// Holds resources in memory, and optionally saves them to a directory so that they outlive the
+/// process.
+///
+/// A saved store has a directory of chunks holding their raw bytes and a directory of the other
+/// objects as JSON, each file named after the object's hash, along with a file of named roots.
+/// Saved objects are only read once they're needed, and chunks are read every time rather than
+/// kept in memory, so opening a store doesn't read it all and it can hold more than fits in
+/// memory.
+#[derive(Debug)]
 pub struct LocalStore {
-    resources: BTreeMap<Object, Resource>,
+    /// Every resource in the store, which saved ones are loaded into as they're read
+    resources: RefCell<BTreeMap<Object, Stored>>,
+    roots: BTreeMap<String, Object>,
+    /// Bytes of file contents held (see [`StoreUsage::bytes`]), or for a saved store, bytes of
+    /// the files in its directory
+    bytes: u64,
+    /// Directory resources and roots are saved to as they're added
+    dir: Option<PathBuf>,
+    /// Files up to this many bytes are stored inline, see [`LocalStore::set_inline_size`]
+    inline_size: u64,
 }
 
 impl LocalStore {
     pub fn new() -> Self {
         Self {
-            resources: BTreeMap::new(),
+            resources: RefCell::new(BTreeMap::new()),
+            roots: BTreeMap::new(),
+            bytes: 0,
+            dir: None,
+            inline_size: DEFAULT_INLINE_SIZE,
         }
     }
 
-    pub fn add_resource(&mut self, resource: Resource) {
-        let object = Object::from(&resource);
-        self.resources.entry(object).or_insert(resource);
+    /// Returns the size up to which files are stored inline rather than in a chunk.
+    pub fn inline_size(&self) -> u64 {
+        self.inline_size
     }
 
-    pub fn create_file(&mut self, contents: &[u8]) -> Object {
-        let chunks = Chunk::chunks_from_data(contents);
-        let chunk_objects = chunks.into_iter().map(|chunk| {
-            let arc = Arc::new(chunk);
-            let chunk_object = Object::from(&arc.clone().into());
+    /// Sets the size up to which files are stored inline rather than in a chunk.
+    ///
+    /// Anything that creates files for the store should use it, so that the same contents
+    /// always make the same object.
+    pub fn set_inline_size(&mut self, inline_size: u64) {
+        self.inline_size = inline_size;
+    }
 
-            self.resources.entry(chunk_object).or_insert(arc.into());
+    /// Opens the store saved in `dir`, creating it if it doesn't exist, and saves everything
+    /// added from now on there too.
+    ///
+    /// Only the names of the saved objects are read, the objects themselves are read when
+    /// they're needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let mut store = Self::new();
+        for subdir in [OBJECTS_DIR, CHUNKS_DIR] {
+            let path = dir.join(subdir);
+            fs::create_dir_all(&path)?;
+
+            for (object, len) in saved_objects(&path)? {
+                let stored = match subdir {
+                    CHUNKS_DIR => Stored::Chunk,
+                    _ => Stored::Saved,
+                };
+                store.resources.get_mut().insert(object, stored);
+                store.bytes += len;
+            }
+        }
 
-            chunk_object
+        match fs::read(dir.join(ROOTS_FILE)) {
+            Ok(contents) => store.roots = serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        store.dir = Some(dir.to_path_buf());
+
+        Ok(store)
+    }
+
+    /// Points the named root `name` at `object`, creating it if it doesn't exist.
+    pub fn set_root(&mut self, name: &str, object: Object) {
+        self.roots.insert(name.to_string(), object);
+
+        if let Some(dir) = &self.dir {
+            let saved = serde_json::to_vec(&self.roots)
+                .map_err(Error::other)
+                .and_then(|contents| write_atomically(&dir.join(ROOTS_FILE), &contents));
+            if let Err(err) = saved {
+                eprintln!("Got error {} while saving named roots", err);
+            }
+        }
+    }
+
+    /// Records `commit` as the next version of the named root `name` and returns the commit the
+    /// root now points to.
+    ///
+    /// The root's current commit becomes the first parent. If the tree hasn't changed and there
+    /// are no other parents, the current commit is kept instead of making an empty one.
+    pub fn commit(&mut self, name: &str, mut commit: Commit) -> Object {
+        let current = self.roots.get(name).and_then(|obj| {
+            let current: Arc<Commit> = self.get(obj)?;
+            Some((*obj, current))
         });
 
-        let file = File::new(chunk_objects.collect(), contents.len() as u64);
-        let resource: Resource = Arc::new(file).into();
+        if let Some((object, current)) = current {
+            if current.tree == commit.tree && commit.parents.is_empty() {
+                return object;
+            }
+            commit.parents.insert(0, object);
+        }
+
+        self.commit_with_parents(name, commit)
+    }
+
+    /// Points the named root `name` at `commit` with only the parents it already has, unlike
+    /// [`LocalStore::commit`], and returns the new commit.
+    pub fn commit_with_parents(&mut self, name: &str, commit: Commit) -> Object {
+        let resource: Resource = Arc::new(commit).into();
         let object = Object::from(&resource);
-        self.resources.entry(object).or_insert(resource);
+        self.add_resource(resource);
+        self.set_root(name, object);
 
         object
     }
 
+    pub fn add_resource(&mut self, resource: Resource) {
+        let object = Object::from(&resource);
+        self.insert(object, resource);
+    }
+
+    pub fn add_resources<I: IntoIterator<Item = Resource>>(&mut self, resources: I) {
+        for resource in resources {
+            self.add_resource(resource);
+        }
+    }
+
+    /// Adds resources along with the objects they hash to, which the caller has already worked
+    /// out, so that they aren't hashed again under the store's lock.
+    pub fn add_hashed_resources<I: IntoIterator<Item = (Object, Resource)>>(
+        &mut self,
+        resources: I,
+    ) {
+        for (object, resource) in resources {
+            self.insert(object, resource);
+        }
+    }
+
+    pub fn create_file(&mut self, contents: &[u8]) -> Object {
+        self.create_file_from_reader(contents)
+            .expect("reading from a slice should not fail")
+    }
+
+    /// Creates a file from a reader, reading it one chunk at a time (see [`FileBuilder`]).
+    ///
+    /// Every chunk is kept in the store, which holds them in memory unless it's saved to a
+    /// directory, so files only have to fit in memory for stores that aren't saved.
+    pub fn create_file_from_reader<R: Read>(&mut self, reader: R) -> io::Result<Object> {
+        let mut builder = FileBuilder::new(self.inline_size);
+        for chunk in Chunk::chunks_from_reader(reader) {
+            if let Some((object, resource)) = builder.push(chunk?) {
+                self.insert(object, resource);
+            }
+        }
+
+        let (object, resources) = builder.finish();
+        self.add_hashed_resources(resources);
+
+        Ok(object)
+    }
+
+    /// Adds a resource that has already been hashed to `object`, saving it if the store has a
+    /// directory.
+    fn insert(&mut self, object: Object, resource: Resource) {
+        let Entry::Vacant(entry) = self.resources.get_mut().entry(object) else {
+            return;
+        };
+
+        let saved = self
+            .dir
+            .as_ref()
+            .map(|dir| save_object(dir, &object, &resource));
+        match saved {
+            Some(Ok(len)) => {
+                self.bytes += len;
+                entry.insert(match resource {
+                    Resource::Chunk(_) => Stored::Chunk,
+                    resource => Stored::Loaded(resource),
+                });
+            }
+            saved => {
+                // the resource is still usable for as long as we're running
+                if let Some(Err(err)) = saved {
+                    eprintln!("Got error {} while saving {}", err, object);
+                }
+                self.bytes += match &resource {
+                    Resource::Chunk(chunk) => chunk.data.len() as u64,
+                    Resource::File(file) => file.data.as_ref().map_or(0, Vec::len) as u64,
+                    Resource::Directory(_) | Resource::Commit(_) => 0,
+                };
+                entry.insert(Stored::Loaded(resource));
+            }
+        }
+    }
+
+    /// Reads a saved resource, keeping it in memory from now on unless it's a chunk.
+    fn load(
+        &self,
+        resources: &mut BTreeMap<Object, Stored>,
+        object: &Object,
+    ) -> io::Result<Resource> {
+        let dir = self.dir.as_ref().ok_or(Error::from(ErrorKind::NotFound))?;
+        let name = object.hash.encode_hex::<String>();
+
+        match resources.get(object) {
+            Some(Stored::Loaded(resource)) => Ok(resource.clone()),
+            Some(Stored::Chunk) => {
+                let data = fs::read(dir.join(CHUNKS_DIR).join(name))?;
+                Ok(Arc::new(Chunk { data }).into())
+            }
+            Some(Stored::Saved) => {
+                let resource: Resource =
+                    serde_json::from_slice(&fs::read(dir.join(OBJECTS_DIR).join(name))?)?;
+                let stored = match &resource {
+                    Resource::Chunk(_) => Stored::Saved,
+                    resource => Stored::Loaded(resource.clone()),
+                };
+                resources.insert(*object, stored);
+                Ok(resource)
+            }
+            None => Err(Error::from(ErrorKind::NotFound)),
+        }
+    }
+
     pub fn create_directory(&mut self, contents: BTreeMap<String, Object>) -> Object {
-        let dir = Directory::new(contents);
+        let (dir, shards) = Directory::build(contents);
+        self.add_resources(shards);
         let resource: Resource = Arc::new(dir).into();
         let object = Object::from(&resource);
-        self.resources.entry(object).or_insert(resource);
+        self.insert(object, resource);
 
         object
     }
 }
 
+impl Default for LocalStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the objects saved in `dir` along with the sizes of their files.
+fn saved_objects(dir: &Path) -> io::Result<Vec<(Object, u64)>> {
+    let mut objects = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // objects that were being written when the process stopped
+        if path.extension().is_some() {
+            continue;
+        }
+
+        let mut hash = [0; 32];
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if hex::decode_to_slice(name, &mut hash).is_err() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} isn't named after a hash", path.display()),
+            ));
+        }
+        objects.push((Object::new(hash), entry.metadata()?.len()));
+    }
+
+    Ok(objects)
+}
+
+/// Saves a resource under `dir`, chunks as their raw bytes and anything else as JSON, and
+/// returns the size of the file it's saved in.
+fn save_object(dir: &Path, object: &Object, resource: &Resource) -> io::Result<u64> {
+    let name = object.hash.encode_hex::<String>();
+    let path = match resource {
+        Resource::Chunk(_) => dir.join(CHUNKS_DIR).join(name),
+        _ => dir.join(OBJECTS_DIR).join(name),
+    };
+    if let Ok(metadata) = fs::metadata(&path) {
+        return Ok(metadata.len());
+    }
+
+    match resource {
+        Resource::Chunk(chunk) => write_atomically(&path, &chunk.data)?,
+        _ => write_atomically(&path, &serde_json::to_vec(resource).map_err(Error::other)?)?,
+    }
+    Ok(fs::metadata(&path)?.len())
+}
+
+/// Writes `contents` to `path` through a temporary file, so that nothing is left half written if
+/// the process stops.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(temporary, path)
+}
+
 impl ContentAddressedStore for LocalStore {
     fn get_resource(&self, object: &Object) -> Option<Resource> {
-        self.resources.get(object).cloned()
+        let mut resources = self.resources.borrow_mut();
+        if let Some(Stored::Loaded(resource)) = resources.get(object) {
+            return Some(resource.clone());
+        }
+
+        match self.load(&mut resources, object) {
+            Ok(resource) => Some(resource),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                eprintln!("Got error {} while reading {}", err, object);
+                None
+            }
+        }
     }
 
     fn has(&self, object: &Object) -> bool {
-        self.resources.contains_key(object)
+        self.resources.borrow().contains_key(object)
     }
 
+    /// Lists every file, directory and commit, leaving out the shards of large directories and
+    /// the index nodes of large files, which only make sense as part of them.
+    ///
+    /// This reads every saved object that hasn't been read yet.
     fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError> {
-        let accessible_objects = self
-            .resources
+        let mut resources = self.resources.borrow_mut();
+        let unread: Vec<_> = resources
+            .iter()
+            .filter(|(_, stored)| matches!(stored, Stored::Saved))
+            .map(|(object, _)| *object)
+            .collect();
+        for object in unread {
+            if let Err(err) = self.load(&mut resources, &object) {
+                eprintln!("Got error {} while reading {}", err, object);
+            }
+        }
+
+        let loaded = resources
+            .iter()
+            .filter_map(|(object, stored)| match stored {
+                Stored::Loaded(resource) => Some((object, resource)),
+                Stored::Saved | Stored::Chunk => None,
+            })
+            .collect::<Vec<_>>();
+        let parts = loaded
+            .iter()
+            .flat_map(|(_, resource)| -> Box<dyn Iterator<Item = Object> + '_> {
+                match resource {
+                    Resource::Directory(dir) => Box::new(dir.shard_objects()),
+                    Resource::File(file) if file.depth > 0 => {
+                        Box::new(file.contents.iter().copied())
+                    }
+                    _ => Box::new(std::iter::empty()),
+                }
+            })
+            .collect::<HashSet<_>>();
+
+        let accessible_objects = loaded
             .iter()
-            .filter_map(|(obj, resource)| {
+            .filter(|(obj, _)| !parts.contains(obj))
+            .filter_map(|&(obj, resource)| {
                 let file: Result<&File, ()> = resource.try_into();
                 let directory: Result<&Directory, ()> = resource.try_into();
+                let commit: Result<&Commit, ()> = resource.try_into();
 
-                if file.is_ok() || directory.is_ok() {
+                if file.is_ok() || directory.is_ok() || commit.is_ok() {
                     Some(*obj)
                 } else {
                     None
@@ -81,6 +421,65 @@ impl ContentAddressedStore for LocalStore {
 
         Ok(accessible_objects)
     }
+
+    fn objects_with_prefix(&self, prefix: &str) -> Vec<Object> {
+        let Some(lowest) = Object::lowest_with_prefix(prefix) else {
+            return Vec::new();
+        };
+
+        // objects are sorted by hash, so the matches are all next to each other
+        self.resources
+            .borrow()
+            .range(lowest..)
+            .take_while(|(object, _)| object.has_prefix(prefix))
+            .filter(|(_, stored)| {
+                !matches!(stored, Stored::Chunk | Stored::Loaded(Resource::Chunk(_)))
+            })
+            .map(|(object, _)| *object)
+            .collect()
+    }
+
+    /// Resources are saved to the store's directory if it has one, so more can be added for as
+    /// long as its filesystem has space, and otherwise for as long as there is memory free.
+    fn usage(&self) -> StoreUsage {
+        let available = match &self.dir {
+            Some(dir) => free_space(dir),
+            None => free_memory(),
+        };
+
+        StoreUsage {
+            objects: self.resources.borrow().len() as u64,
+            bytes: self.bytes,
+            available,
+        }
+    }
+
+    fn roots(&self) -> BTreeMap<String, Object> {
+        self.roots.clone()
+    }
+
+    fn get_root(&self, name: &str) -> Option<Object> {
+        self.roots.get(name).copied()
+    }
+}
+
+/// Returns the bytes of memory free.
+fn free_memory() -> Option<u64> {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    match unsafe { libc::sysinfo(&mut info) } {
+        0 => Some(info.freeram as u64 * info.mem_unit as u64),
+        _ => None,
+    }
+}
+
+/// Returns the bytes free to unprivileged users on the filesystem holding `dir`.
+fn free_space(dir: &Path) -> Option<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat.f_bavail as u64 * stat.f_frsize as u64),
+        _ => None,
+    }
 }
 
 impl ContentAddressedStore for Arc<Mutex<LocalStore>> {
@@ -95,4 +494,185 @@ impl ContentAddressedStore for Arc<Mutex<LocalStore>> {
     fn accessible_objects(&self) -> Result<Vec<Object>, PathResolutionError> {
         self.lock().unwrap().accessible_objects()
     }
+
+    fn objects_with_prefix(&self, prefix: &str) -> Vec<Object> {
+        self.lock().unwrap().objects_with_prefix(prefix)
+    }
+
+    fn usage(&self) -> StoreUsage {
+        self.lock().unwrap().usage()
+    }
+
+    fn roots(&self) -> BTreeMap<String, Object> {
+        self.lock().unwrap().roots()
+    }
+
+    fn get_root(&self, name: &str) -> Option<Object> {
+        self.lock().unwrap().get_root(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cas::{chunk::CHUNK_SIZE, directory::SHARD_THRESHOLD, file::FILE_FANOUT};
+
+    use super::*;
+
+    #[test]
+    fn finds_objects_by_prefix() {
+        let mut store = LocalStore::new();
+        let objects: Vec<_> = (0..200)
+            .map(|i| store.create_file(format!("file {i}").as_bytes()))
+            .collect();
+
+        for object in &objects {
+            let hex = object.to_string();
+            for len in [1, 2, 4, 64] {
+                let prefix = &hex[..len];
+                let mut expected: Vec<_> = objects
+                    .iter()
+                    .filter(|other| other.has_prefix(prefix))
+                    .copied()
+                    .collect();
+                expected.sort();
+                assert_eq!(store.objects_with_prefix(prefix), expected);
+            }
+        }
+
+        let file = objects[0];
+        let prefix = file.to_string()[..12].to_uppercase();
+        assert_eq!(store.resolve_path(&format!("/{prefix}")).unwrap(), file);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dfs-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reports_the_space_free_where_the_store_is_saved() {
+        let dir = temp_dir("usage");
+        let store = LocalStore::open(&dir).unwrap();
+
+        // other tests may be writing to the same filesystem in the meantime
+        let available = store.usage().available.unwrap();
+        let free = free_space(&dir).unwrap();
+        assert!(available.abs_diff(free) < 1 << 30);
+
+        assert!(LocalStore::new().usage().available.is_some());
+    }
+
+    #[test]
+    fn saves_chunks_as_raw_bytes() {
+        let dir = temp_dir("raw");
+        let mut store = LocalStore::open(&dir).unwrap();
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let file = store.create_file(&data);
+
+        let chunks: Vec<_> = fs::read_dir(dir.join(CHUNKS_DIR)).unwrap().collect();
+        let sizes: HashSet<_> = chunks
+            .iter()
+            .map(|chunk| chunk.as_ref().unwrap().metadata().unwrap().len())
+            .collect();
+        assert_eq!(sizes, HashSet::from([CHUNK_SIZE, 10]));
+        // chunks are read back from their files rather than kept around
+        let stored = store.resources.borrow();
+        assert_eq!(
+            stored
+                .values()
+                .filter(|stored| matches!(stored, Stored::Chunk))
+                .count(),
+            2
+        );
+        drop(stored);
+
+        let file: Arc<File> = store.get(&file).unwrap();
+        assert_eq!(store.read_file(file, 0, data.len() as u64).unwrap(), data);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_saved_objects_once_they_are_needed() {
+        let dir = temp_dir("reopen");
+        let mut store = LocalStore::open(&dir).unwrap();
+        let data = vec![7; CHUNK_SIZE as usize * 2];
+        let file = store.create_file(&data);
+        let directory = store.create_directory(BTreeMap::from([("file".to_string(), file)]));
+        store.set_root("root", directory);
+        let usage = store.usage();
+        drop(store);
+
+        let store = LocalStore::open(&dir).unwrap();
+        assert!(store
+            .resources
+            .borrow()
+            .values()
+            .all(|stored| matches!(stored, Stored::Saved | Stored::Chunk)));
+        assert_eq!(store.usage().objects, usage.objects);
+        assert_eq!(store.usage().bytes, usage.bytes);
+
+        assert_eq!(store.resolve_path("/root/file").unwrap(), file);
+        let file: Arc<File> = store.get(&file).unwrap();
+        assert_eq!(store.read_file(file, 0, data.len() as u64).unwrap(), data);
+
+        let mut accessible = store.accessible_objects().unwrap();
+        accessible.sort();
+        let mut expected = vec![directory, store.resolve_path("/root/file").unwrap()];
+        expected.sort();
+        assert_eq!(accessible, expected);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn commits_with_only_the_given_parents() {
+        let mut store = LocalStore::new();
+        let (one, two) = (store.create_file(b"one"), store.create_file(b"two"));
+        let first = store.commit("root", Commit::new(one, vec![], "me", "first"));
+        let other = store.commit("other", Commit::new(two, vec![], "me", "other"));
+
+        let second = store.commit("root", Commit::new(two, vec![other], "me", "second"));
+        let commit: Arc<Commit> = store.get(&second).unwrap();
+        assert_eq!(commit.parents, [first, other]);
+
+        let merge = Commit::new(one, vec![other, first], "me", "merge");
+        let merged = store.commit_with_parents("root", merge);
+        let commit: Arc<Commit> = store.get(&merged).unwrap();
+        assert_eq!(commit.parents, [other, first]);
+        assert_eq!(store.get_root("root"), Some(merged));
+    }
+
+    #[test]
+    fn leaves_shards_out_of_accessible_objects() {
+        let mut store = LocalStore::new();
+        let file = store.create_file(b"file");
+        let contents = (0..SHARD_THRESHOLD * 4)
+            .map(|i| (format!("entry-{i}"), file))
+            .collect();
+        let directory = store.create_directory(contents);
+
+        let mut accessible = store.accessible_objects().unwrap();
+        accessible.sort();
+        let mut expected = vec![file, directory];
+        expected.sort();
+        assert_eq!(accessible, expected);
+    }
+
+    #[test]
+    fn leaves_file_index_nodes_out_of_accessible_objects() {
+        let mut store = LocalStore::new();
+        let chunks = (0..FILE_FANOUT * 3)
+            .map(|i| Object::new([(i % 251) as u8; 32]))
+            .collect::<Vec<_>>();
+        let (file, nodes) = File::build(chunks, CHUNK_SIZE * FILE_FANOUT as u64 * 3);
+        assert!(!nodes.is_empty());
+        store.add_resources(nodes);
+        let resource: Resource = Arc::new(file).into();
+        let file = Object::from(&resource);
+        store.add_resource(resource);
+
+        assert_eq!(store.accessible_objects().unwrap(), [file]);
+    }
 }